@@ -0,0 +1,149 @@
+// Compares the bitboard with the original `Vec<Vec<Team>>` board, and times
+// the solver on the empty board.
+
+use std::time::{Duration, Instant};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use shuttlings_cch24::{
+    board::{Board, Team},
+    reference::VecBoard,
+    solver::{Solver, MAX_NODES},
+};
+
+const N: usize = 4;
+
+// Random games played until the board is full, with the moves that were
+// accepted.
+fn games(count: usize) -> Vec<Vec<(usize, Team)>> {
+    let mut rng = StdRng::seed_from_u64(2024);
+
+    (0..count)
+        .map(|_| {
+            let mut board: Board<N> = Board::new();
+            let mut moves = Vec::new();
+
+            while !board.full() {
+                let col = rng.gen_range(1..=N);
+                let tile = if rng.gen_bool(0.5) { Team::COOKIE } else { Team::MILK };
+
+                if board.place(col, tile).is_ok() {
+                    moves.push((col, tile));
+                }
+            }
+
+            moves
+        })
+        .collect()
+}
+
+fn bench_winner(c: &mut Criterion) {
+    let games = games(256);
+
+    let boards = games.iter()
+        .map(|moves| {
+            let mut board: Board<N> = Board::new();
+
+            for &(col, tile) in moves {
+                let _ = board.place(col, tile);
+            }
+
+            board
+        })
+        .collect::<Vec<_>>();
+
+    let vec_boards = games.iter()
+        .map(|moves| {
+            let mut board: VecBoard<N> = VecBoard::new();
+
+            for &(col, tile) in moves {
+                let _ = board.place(col, tile);
+            }
+
+            board
+        })
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("winner");
+
+    group.bench_function(BenchmarkId::new("bitboard", N), |b| {
+        b.iter(|| {
+            for board in &boards {
+                black_box(black_box(board).winner());
+            }
+        })
+    });
+
+    group.bench_function(BenchmarkId::new("vec", N), |b| {
+        b.iter(|| {
+            for board in &vec_boards {
+                black_box(black_box(board).winner());
+            }
+        })
+    });
+
+    group.finish();
+}
+
+// Replays whole games, checking for a winner after every move like the
+// /12/place handler does.
+fn bench_game(c: &mut Criterion) {
+    let games = games(256);
+
+    let mut group = c.benchmark_group("game");
+
+    group.bench_function(BenchmarkId::new("bitboard", N), |b| {
+        b.iter(|| {
+            for moves in &games {
+                let mut board: Board<N> = Board::new();
+
+                for &(col, tile) in moves {
+                    let _ = board.place(col, tile);
+                    black_box(board.winner());
+                    black_box(board.full());
+                }
+            }
+        })
+    });
+
+    group.bench_function(BenchmarkId::new("vec", N), |b| {
+        b.iter(|| {
+            for moves in &games {
+                let mut board: VecBoard<N> = VecBoard::new();
+
+                for &(col, tile) in moves {
+                    let _ = board.place(col, tile);
+                    black_box(board.winner());
+                    black_box(board.full());
+                }
+            }
+        })
+    });
+
+    group.finish();
+}
+
+// Analyzes every column of the empty board with the same table, like the
+// /12/analyze handler does.
+fn bench_solve(c: &mut Criterion) {
+    let board: Board<N> = Board::new();
+
+    let mut group = c.benchmark_group("solve");
+
+    group.sample_size(10);
+
+    group.bench_function(BenchmarkId::new("empty", N), |b| {
+        b.iter(|| {
+            let mut solver = Solver::new(MAX_NODES, Instant::now() + Duration::from_secs(3600));
+
+            for col in 1..=N {
+                black_box(solver.solve(black_box(&board), col, Team::COOKIE, Team::MILK).unwrap());
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_winner, bench_game, bench_solve);
+criterion_main!(benches);
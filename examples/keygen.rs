@@ -0,0 +1,49 @@
+// Prints a new key for the gift keyring, to be appended to keyring.toml:
+//
+//   cargo run --example keygen -- [HS256|RS256|EdDSA] [not_before] >> keyring.toml
+//
+// The service never makes up keys of its own, so that every instance and
+// every deploy signs with the keys in the file. Give a new key a not_before
+// in the future, and its predecessor a retire_at at least one token lifetime
+// after that, to rotate without rejecting tokens in flight.
+
+use std::{
+    env,
+    process,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use jwt_simple::{
+    prelude::{Ed25519KeyPair, HS256Key, RS256KeyPair},
+    reexports::ct_codecs::{Base64UrlSafeNoPadding, Encoder},
+};
+
+const RSA_MODULUS_BITS: usize = 2048;
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let algorithm = args.next().unwrap_or_else(|| String::from("HS256"));
+    let not_before = match args.next() {
+        Some(s) => s.parse().unwrap_or_else(|_| usage()),
+        None => SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+    };
+
+    let secret = match algorithm.as_str() {
+        "HS256" => HS256Key::generate().to_bytes(),
+        "RS256" => RS256KeyPair::generate(RSA_MODULUS_BITS).unwrap().to_der().unwrap(),
+        "EdDSA" => Ed25519KeyPair::generate().to_bytes(),
+        _ => usage(),
+    };
+
+    println!();
+    println!("[[keys]]");
+    println!("kid = \"{:016x}\"", rand::random::<u64>());
+    println!("alg = \"{}\"", algorithm);
+    println!("secret = \"{}\"", Base64UrlSafeNoPadding::encode_to_string(secret).unwrap());
+    println!("not_before = {}", not_before);
+}
+
+fn usage() -> ! {
+    eprintln!("usage: keygen [HS256|RS256|EdDSA] [not_before]");
+    process::exit(2);
+}
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS games (
+    id UUID PRIMARY KEY,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    finished_at TIMESTAMPTZ,
+    winner TEXT
+);
+
+CREATE TABLE IF NOT EXISTS moves (
+    game_id UUID NOT NULL REFERENCES games (id) ON DELETE CASCADE,
+    seq INT NOT NULL,
+    team TEXT NOT NULL,
+    col INT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (game_id, seq)
+);
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS players (
+    name TEXT PRIMARY KEY,
+    rating DOUBLE PRECISION NOT NULL DEFAULT 1200,
+    wins INT NOT NULL DEFAULT 0,
+    losses INT NOT NULL DEFAULT 0,
+    draws INT NOT NULL DEFAULT 0,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS game_players (
+    game_id UUID NOT NULL REFERENCES games (id) ON DELETE CASCADE,
+    team TEXT NOT NULL,
+    player TEXT NOT NULL REFERENCES players (name),
+    PRIMARY KEY (game_id, team)
+);
+
+CREATE INDEX IF NOT EXISTS game_players_player_idx ON game_players (player);
@@ -0,0 +1,3 @@
+-- The names and glyphs of the teams playing, as a JSON array of
+-- {"name": ..., "glyph": ...} objects. NULL stands for cookie and milk.
+ALTER TABLE games ADD COLUMN IF NOT EXISTS teams JSONB;
@@ -0,0 +1,19 @@
+CREATE TABLE IF NOT EXISTS refresh_tokens (
+    -- SHA-256 of the token, which is only ever known to its holder
+    hash BYTEA PRIMARY KEY,
+    -- The tokens rotated from the same one issued by /16/wrap
+    family UUID NOT NULL,
+    -- The gift the token is refreshed with
+    payload JSONB NOT NULL,
+    -- The gift token issued along with it
+    access_jti TEXT NOT NULL,
+    access_expires_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL,
+    -- NULL until the token is rotated
+    used_at TIMESTAMPTZ,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_family ON refresh_tokens (family);
+CREATE INDEX IF NOT EXISTS refresh_tokens_access_jti ON refresh_tokens (access_jti);
+CREATE INDEX IF NOT EXISTS refresh_tokens_expires_at ON refresh_tokens (expires_at);
@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS milk_buckets (
+    -- The client, as in "ip:10.0.0.1" or "api_key:<hex SHA-256 of the key>"
+    key TEXT PRIMARY KEY,
+    -- As of refilled_at, possibly a fraction
+    liters DOUBLE PRECISION NOT NULL,
+    refilled_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS milk_buckets_refilled_at ON milk_buckets (refilled_at);
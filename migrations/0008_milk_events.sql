@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS milk_events (
+    id BIGSERIAL PRIMARY KEY,
+    at TIMESTAMPTZ NOT NULL,
+    -- The client, as in "ip:10.0.0.1" or "api_key:<hex SHA-256 of the key>"
+    client TEXT NOT NULL,
+    liters BIGINT NOT NULL,
+    -- Whether the milk was withdrawn, or refused for lack of it
+    granted BOOLEAN NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS milk_events_at ON milk_events (at);
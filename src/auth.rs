@@ -0,0 +1,248 @@
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, MatchedPath, Request, State},
+    http::{header, request::Parts, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use axum_extra::extract::CookieJar;
+use jwt_simple::{claims::JWTClaims, reexports::ct_codecs::{Base64, Decoder}};
+use serde::Serialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{day16::{self, TokenError}, AppState};
+
+// The claims of a gift token the handlers care about.
+#[derive(Clone, Debug, Serialize)]
+pub(super) struct GiftClaims {
+    #[serde(rename = "sub")]
+    pub(super) subject: Option<String>,
+    #[serde(rename = "jti")]
+    pub(super) jwt_id: Option<String>,
+    // In seconds since the epoch, if it expires.
+    #[serde(rename = "exp")]
+    pub(super) expires_at: Option<u64>,
+    pub(super) roles: Vec<String>,
+    // The gift, roles included.
+    pub(super) custom: Value,
+}
+
+impl GiftClaims {
+    // Roles are a list of strings in the custom claim, anything else grants
+    // none.
+    fn new(claims: JWTClaims<Value>, roles_claim: &str) -> GiftClaims {
+        let roles = match claims.custom.get(roles_claim) {
+            Some(Value::Array(roles)) => roles.iter()
+                .filter_map(|role| role.as_str().map(String::from))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        GiftClaims {
+            subject: claims.subject,
+            jwt_id: claims.jwt_id,
+            expires_at: claims.expires_at.map(|exp| exp.as_secs()),
+            roles,
+            custom: claims.custom,
+        }
+    }
+}
+
+// The holder of a valid, unrevoked gift token.
+#[derive(Clone, Debug)]
+pub(super) struct Principal {
+    pub(super) claims: GiftClaims,
+}
+
+impl Principal {
+    fn has_any_role(&self, roles: &[String]) -> bool {
+        roles.iter().any(|role| self.claims.roles.contains(role))
+    }
+}
+
+pub(super) enum AuthError {
+    // No token, or not a valid one.
+    Unauthenticated(TokenError),
+    // A valid token, without any of the roles required.
+    Forbidden,
+    // Client credentials missing or wrong.
+    InvalidClient,
+    Internal,
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> Response {
+        match self {
+            AuthError::Unauthenticated(e) => (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, HeaderValue::from_static("Bearer"))],
+                String::from(e.code()),
+            ).into_response(),
+            AuthError::Forbidden => (StatusCode::FORBIDDEN, String::from("forbidden")).into_response(),
+            AuthError::InvalidClient => (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, HeaderValue::from_static("Basic"))],
+                Json(json!({"error": "invalid_client"})),
+            ).into_response(),
+            AuthError::Internal => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+// The token in the Authorization header, or else in the gift cookie.
+fn token(parts: &Parts) -> Result<String, TokenError> {
+    if let Some(value) = parts.headers.get(header::AUTHORIZATION) {
+        return value.to_str()
+            .ok()
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|jwt| jwt.trim().to_string())
+            .ok_or(TokenError::Malformed);
+    }
+
+    CookieJar::from_headers(&parts.headers)
+        .get(day16::GIFT_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .ok_or(TokenError::Missing)
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Principal {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        // Already authenticated by the middleware.
+        if let Some(principal) = parts.extensions.get::<Principal>() {
+            return Ok(principal.clone());
+        }
+
+        let claims = token(parts)
+            .and_then(|jwt| day16::verify_gift(state, &jwt))
+            .map_err(AuthError::Unauthenticated)?;
+
+        match day16::is_revoked(&state.pool, &claims).await {
+            Ok(false) => {},
+            Ok(true) => return Err(AuthError::Unauthenticated(TokenError::Revoked)),
+            Err(_) => return Err(AuthError::Internal),
+        }
+
+        Ok(Principal { claims: GiftClaims::new(claims, &state.config.auth.roles_claim) })
+    }
+}
+
+// A service that authenticated with the credentials of one of the clients in
+// the auth config.
+pub(super) struct Client;
+
+// The client ID and secret in an `Authorization: Basic` header.
+fn basic_credentials(parts: &Parts) -> Option<(String, String)> {
+    let encoded = parts.headers.get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(Base64::decode_to_vec(encoded.trim(), None).ok()?).ok()?;
+    let (id, secret) = decoded.split_once(':')?;
+
+    Some((id.to_string(), secret.to_string()))
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for Client {
+    type Rejection = AuthError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let (id, secret) = basic_credentials(parts).ok_or(AuthError::InvalidClient)?;
+        let hash = format!("{:x}", Sha256::digest(secret.as_bytes()));
+
+        match state.config.auth.clients.get(&id) {
+            Some(expected) if expected.eq_ignore_ascii_case(&hash) => Ok(Client),
+            _ => Err(AuthError::InvalidClient),
+        }
+    }
+}
+
+// Let through only the callers with one of the roles the route requires, if
+// it is listed in the auth config, and hand the handlers who they are.
+pub(super) async fn authorize(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let route = request.extensions()
+        .get::<MatchedPath>()
+        .map(|path| format!("{} {}", request.method(), path.as_str()));
+
+    let roles = match route.and_then(|route| state.config.auth.roles(&route)) {
+        Some(roles) => roles,
+        None => return Ok(next.run(request).await),
+    };
+
+    let (mut parts, body) = request.into_parts();
+    let principal = Principal::from_request_parts(&mut parts, &state).await?;
+
+    if !principal.has_any_role(&roles) {
+        return Err(AuthError::Forbidden);
+    }
+
+    parts.extensions.insert(principal);
+
+    Ok(next.run(Request::from_parts(parts, body)).await)
+}
+
+#[cfg(test)]
+mod test {
+    use axum::http::Request;
+
+    use super::*;
+
+    fn parts(header: (header::HeaderName, &str)) -> Parts {
+        Request::builder()
+            .header(header.0, header.1)
+            .body(())
+            .unwrap()
+            .into_parts()
+            .0
+    }
+
+    #[test]
+    fn test_gift_claims() {
+        let claims = jwt_simple::claims::Claims::with_custom_claims(
+            json!({"gift": "cookie", "roles": ["admin", 1, "elf"]}),
+            jwt_simple::prelude::Duration::from_secs(60),
+        ).with_subject("santa").with_jwt_id("a");
+        let expires_at = claims.expires_at.unwrap().as_secs();
+        let claims = GiftClaims::new(claims, "roles");
+
+        assert_eq!(claims.subject.as_deref(), Some("santa"));
+        assert_eq!(claims.jwt_id.as_deref(), Some("a"));
+        assert_eq!(claims.expires_at, Some(expires_at));
+        assert_eq!(claims.roles, ["admin", "elf"]);
+        assert_eq!(claims.custom["gift"], "cookie");
+
+        let claims = GiftClaims::new(jwt_simple::claims::Claims::with_custom_claims(
+            json!({"roles": "admin"}),
+            jwt_simple::prelude::Duration::from_secs(60),
+        ), "roles");
+
+        assert!(claims.roles.is_empty());
+        assert_eq!(claims.subject, None);
+    }
+
+    #[test]
+    fn test_token() {
+        assert_eq!(token(&parts((header::AUTHORIZATION, "Bearer a.b.c"))), Ok(String::from("a.b.c")));
+        assert_eq!(token(&parts((header::AUTHORIZATION, "Basic YTpi"))), Err(TokenError::Malformed));
+        assert_eq!(token(&parts((header::COOKIE, "theme=dark; gift=a.b.c"))), Ok(String::from("a.b.c")));
+        assert_eq!(token(&parts((header::COOKIE, "theme=dark"))), Err(TokenError::Missing));
+    }
+
+    #[test]
+    fn test_basic_credentials() {
+        let credentials = |value| basic_credentials(&parts((header::AUTHORIZATION, value)));
+
+        assert_eq!(credentials("Basic Z2F0ZXdheTpzM2NyZXQ6MQ=="), Some((String::from("gateway"), String::from("s3cret:1"))));
+        assert_eq!(credentials("Basic Z2F0ZXdheQ=="), None);
+        assert_eq!(credentials("Bearer a.b.c"), None);
+    }
+}
@@ -0,0 +1,447 @@
+use std::{collections::HashMap, fs, io::ErrorKind, path::{Path, PathBuf}};
+
+use serde::{Deserialize, Serialize};
+
+// Settings read from config.toml at startup. Every section and setting is
+// optional, and the file itself may be missing, in which case the defaults
+// below apply.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Config {
+    pub(super) gift: GiftConfig,
+    pub(super) decode: DecodeConfig,
+    pub(super) auth: AuthConfig,
+    pub(super) milk: MilkConfig,
+    // [rate_limits."<method> <route>"]: a bucket for each client of the
+    // route, as in "POST /16/wrap". Routes not listed are not limited.
+    pub(super) rate_limits: HashMap<String, RateLimit>,
+}
+
+// [gift]: the tokens issued by /16/wrap.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct GiftConfig {
+    // File holding the signing keys. A fresh key is added to it if it has
+    // none for `algorithm`.
+    pub(super) keyring: PathBuf,
+    pub(super) algorithm: Algorithm,
+    // Lifetime of issued tokens, in seconds.
+    pub(super) lifetime: u64,
+    // Lifetime of the refresh tokens issued with them, in seconds. Each is
+    // used once, for the next token and refresh token.
+    pub(super) refresh_lifetime: u64,
+    // Longest time the published public keys may be cached, in seconds. New
+    // keys should be added to the keyring at least that long before they
+    // start signing.
+    pub(super) jwks_max_age: u64,
+    // Claims stamped into issued tokens.
+    pub(super) issuer: Option<String>,
+    pub(super) audience: Option<String>,
+    pub(super) cookie: CookieConfig,
+    pub(super) encryption: EncryptionConfig,
+    // [gift.validation]: what /16/unwrap accepts.
+    pub(super) validation: ValidationPolicy,
+}
+
+impl Default for GiftConfig {
+    fn default() -> Self {
+        GiftConfig {
+            keyring: PathBuf::from("keyring.toml"),
+            algorithm: Algorithm::HS256,
+            lifetime: 60 * 60,
+            refresh_lifetime: 30 * 24 * 60 * 60,
+            jwks_max_age: 60 * 60,
+            issuer: None,
+            audience: None,
+            cookie: CookieConfig::default(),
+            encryption: EncryptionConfig::default(),
+            validation: ValidationPolicy::default(),
+        }
+    }
+}
+
+// [gift.cookie]: the attributes of the cookie holding the token.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct CookieConfig {
+    pub(super) http_only: bool,
+    pub(super) secure: bool,
+    pub(super) same_site: SameSite,
+    pub(super) path: String,
+    // In seconds, the lifetime of the token unless set.
+    pub(super) max_age: Option<u64>,
+}
+
+impl Default for CookieConfig {
+    fn default() -> Self {
+        CookieConfig {
+            http_only: true,
+            secure: true,
+            same_site: SameSite::Strict,
+            path: String::from("/"),
+            max_age: None,
+        }
+    }
+}
+
+// [gift.encryption]: encrypting issued tokens as JWE, so that their holders
+// cannot read the gifts.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct EncryptionConfig {
+    // 32 random bytes, base64url encoded, the same for every instance. Tokens
+    // are only signed unless set.
+    pub(super) key: Option<String>,
+    // Whether tokens that are only signed are still accepted, while those
+    // issued before encryption was turned on expire.
+    pub(super) accept_unencrypted: bool,
+}
+
+impl Default for EncryptionConfig {
+    fn default() -> Self {
+        EncryptionConfig {
+            key: None,
+            accept_unencrypted: true,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub(super) enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+// Algorithms tokens can be signed with.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub(super) enum Algorithm {
+    HS256,
+    RS256,
+    EdDSA,
+}
+
+// [decode]: the tokens posted to /16/decode.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct DecodeConfig {
+    // Directory of PEM and JWK files with the public keys to accept, in
+    // addition to Santa's. It may be missing.
+    pub(super) keys: PathBuf,
+    // [decode.validation]: what /16/decode accepts.
+    pub(super) validation: ValidationPolicy,
+}
+
+impl Default for DecodeConfig {
+    fn default() -> Self {
+        DecodeConfig {
+            keys: PathBuf::from("keys"),
+            validation: ValidationPolicy::default(),
+        }
+    }
+}
+
+// The routes that change or reveal state for everyone, which need the admin
+// role unless admin_routes is off.
+const ADMIN_ROUTES: &[&str] = &[
+    "POST /9/refill",
+    "PUT /9/config",
+    "GET /9/stats",
+    "POST /12/reset",
+    "POST /12/play/reset",
+    "POST /19/reset",
+];
+
+// [auth]: who may call which endpoints. Callers authenticate with a gift
+// token, in an `Authorization: Bearer` header or the gift cookie.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct AuthConfig {
+    // Custom claim with the roles of the token holder. /16/wrap only issues
+    // tokens with it to the clients below, which is how admins get theirs.
+    pub(super) roles_claim: String,
+    // Whether the reset, refill and milk config and stats endpoints need the
+    // "admin" role, unless listed in routes.
+    pub(super) admin_routes: bool,
+    // [auth.routes]: the roles, any of which a caller needs, by method and
+    // route as in "POST /9/refill". Routes not listed are open to anyone, as
+    // are those listed without any roles.
+    pub(super) routes: HashMap<String, Vec<String>>,
+    // [auth.clients]: the services allowed to introspect tokens and to wrap
+    // gifts with roles, with the hex SHA-256 of their secrets, by client ID.
+    // They authenticate with HTTP Basic.
+    pub(super) clients: HashMap<String, String>,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            roles_claim: String::from("roles"),
+            admin_routes: true,
+            routes: HashMap::new(),
+            clients: HashMap::new(),
+        }
+    }
+}
+
+impl AuthConfig {
+    // The roles, any of which a caller of the route needs, if any.
+    pub(super) fn roles(&self, route: &str) -> Option<Vec<String>> {
+        match self.routes.get(route) {
+            Some(roles) => (!roles.is_empty()).then(|| roles.clone()),
+            None => (self.admin_routes && ADMIN_ROUTES.contains(&route))
+                .then(|| vec![String::from("admin")]),
+        }
+    }
+}
+
+// [milk]: the buckets /9/milk withdraws from, one per client.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct MilkConfig {
+    // Where the milk is kept: "memory" by default, where every instance has
+    // buckets of its own, or "postgres", where they share them. Their tiers
+    // can then only be changed here, not with PUT /9/config.
+    pub(super) backend: MilkBackend,
+    // Seconds after which the bucket of a client that stopped withdrawing is
+    // dropped, at the earliest once it would have been full again.
+    pub(super) idle_timeout: u64,
+    // [milk.tiers.<name>]: the size of the buckets. Clients without an API
+    // key get the "default" one, which must be there.
+    pub(super) tiers: HashMap<String, Tier>,
+    // [milk.api_keys]: the tier of each API key, by the hex SHA-256 of the
+    // key. Unknown keys are ignored.
+    pub(super) api_keys: HashMap<String, String>,
+    // The proxies in front of the service, each of which appends the address
+    // it got the request from to X-Forwarded-For. Clients are told apart by
+    // the address that many hops from the end of it, or by the peer address
+    // without any proxies, in which case X-Forwarded-For is ignored since
+    // anyone can send it. Shuttle puts one proxy in front.
+    pub(super) trusted_proxies: usize,
+    pub(super) telemetry: TelemetryConfig,
+}
+
+impl Default for MilkConfig {
+    fn default() -> Self {
+        MilkConfig {
+            backend: MilkBackend::Memory,
+            idle_timeout: 10 * 60,
+            tiers: HashMap::from([(String::from("default"), Tier::default())]),
+            api_keys: HashMap::new(),
+            trusted_proxies: 0,
+            telemetry: TelemetryConfig::default(),
+        }
+    }
+}
+
+// [milk.telemetry]: the record of what /9/milk granted and refused, for
+// /9/stats.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct TelemetryConfig {
+    // Outcomes kept in memory, the oldest forgotten first.
+    pub(super) capacity: usize,
+    // Whether to also append them to the milk_events table, in batches every
+    // flush_interval seconds, at least one.
+    pub(super) flush: bool,
+    pub(super) flush_interval: u64,
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            capacity: 10_000,
+            flush: false,
+            flush_interval: 10,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum MilkBackend {
+    Memory,
+    // The buckets are refilled continuously rather than every interval, and
+    // those waiting for milk are not served in turn.
+    Postgres,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct Tier {
+    // Liters a full bucket holds.
+    pub(super) capacity: usize,
+    // Liters added every interval, in milliseconds.
+    pub(super) refill: usize,
+    pub(super) interval: u64,
+}
+
+impl Default for Tier {
+    fn default() -> Self {
+        Tier {
+            capacity: 5,
+            refill: 1,
+            interval: 1000,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct RateLimit {
+    pub(super) key: RateLimitKey,
+    // As in the milk tiers.
+    pub(super) capacity: usize,
+    pub(super) refill: usize,
+    pub(super) interval: u64,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        let tier = Tier::default();
+
+        RateLimit {
+            key: RateLimitKey::Client,
+            capacity: tier.capacity,
+            refill: tier.refill,
+            interval: tier.interval,
+        }
+    }
+}
+
+// Whose requests share a bucket.
+#[derive(Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub(super) enum RateLimitKey {
+    // Those with the same API key from the milk config, or else from the
+    // same address.
+    Client,
+    // Those from the same address.
+    Ip,
+    // Everyone's.
+    Global,
+}
+
+// What a token must satisfy beyond a valid signature and, if it has those
+// claims, not being expired nor used before its nbf. Empty lists allow any
+// value, including none.
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(super) struct ValidationPolicy {
+    pub(super) issuers: Vec<String>,
+    pub(super) audiences: Vec<String>,
+    // Custom claims tokens must have.
+    pub(super) required_claims: Vec<String>,
+    // In seconds since the token was issued.
+    pub(super) max_age: Option<u64>,
+    // Clock skew tolerated, in seconds.
+    pub(super) leeway: u64,
+}
+
+impl Default for ValidationPolicy {
+    fn default() -> Self {
+        ValidationPolicy {
+            issuers: Vec::new(),
+            audiences: Vec::new(),
+            required_claims: Vec::new(),
+            max_age: None,
+            leeway: 15 * 60,
+        }
+    }
+}
+
+impl Config {
+    pub(super) fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        match fs::read_to_string(path.as_ref()) {
+            Ok(s) => Config::parse(&s)
+                .map_err(|e| format!("{}: {}", path.as_ref().display(), e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("{}: {}", path.as_ref().display(), e)),
+        }
+    }
+
+    fn parse(s: &str) -> Result<Self, String> {
+        let config: Config = toml::from_str(s).map_err(|e| e.to_string())?;
+        let cookie = &config.gift.cookie;
+
+        // Browsers drop such cookies.
+        if cookie.same_site == SameSite::None && !cookie.secure {
+            return Err(String::from("a cookie with same_site = \"None\" has to be secure"));
+        }
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_defaults() {
+        let config: Config = toml::from_str("").unwrap();
+
+        assert_eq!(config.gift.keyring, PathBuf::from("keyring.toml"));
+        assert_eq!(config.gift.lifetime, 3600);
+
+        let config: Config = toml::from_str("[gift]\nlifetime = 60").unwrap();
+
+        assert_eq!(config.gift.keyring, PathBuf::from("keyring.toml"));
+        assert_eq!(config.gift.lifetime, 60);
+        assert!(config.gift.cookie.secure);
+        assert_eq!(config.gift.cookie.max_age, None);
+
+        let config: Config = toml::from_str("[gift.cookie]\nsame_site = \"Lax\"\nmax_age = 60").unwrap();
+
+        assert_eq!(config.gift.cookie.same_site, SameSite::Lax);
+        assert_eq!(config.gift.cookie.max_age, Some(60));
+        assert_eq!(config.auth.roles("POST /12/play/reset"), Some(vec![String::from("admin")]));
+        assert_eq!(config.auth.roles("POST /9/milk"), None);
+
+        let config: Config = toml::from_str("[auth]\nadmin_routes = false").unwrap();
+
+        assert_eq!(config.auth.roles("POST /19/reset"), None);
+
+        let config: Config = toml::from_str("[auth.routes]\n\"POST /19/reset\" = []").unwrap();
+
+        assert_eq!(config.auth.roles("POST /19/reset"), None);
+        assert_eq!(config.auth.roles("POST /12/reset"), Some(vec![String::from("admin")]));
+
+        let config: Config = toml::from_str("[auth.routes]\n\"POST /9/milk\" = [\"farmer\"]").unwrap();
+
+        assert_eq!(config.auth.routes.len(), 1);
+        assert_eq!(config.auth.roles("POST /9/milk"), Some(vec![String::from("farmer")]));
+
+        let config: Config = toml::from_str("[milk.tiers.gold]\ncapacity = 50").unwrap();
+
+        assert_eq!(config.milk.tiers.len(), 1);
+        assert_eq!(config.milk.tiers["gold"], Tier { capacity: 50, ..Tier::default() });
+        assert_eq!(config.milk.backend, MilkBackend::Memory);
+
+        let config: Config = toml::from_str("[milk]\nbackend = \"postgres\"").unwrap();
+
+        assert_eq!(config.milk.backend, MilkBackend::Postgres);
+        assert_eq!(config.milk.telemetry.capacity, 10_000);
+        assert!(!config.milk.telemetry.flush);
+
+        let config: Config = toml::from_str("[rate_limits.\"POST /16/wrap\"]\nkey = \"ip\"\nrefill = 10").unwrap();
+
+        assert_eq!(
+            config.rate_limits["POST /16/wrap"],
+            RateLimit { key: RateLimitKey::Ip, refill: 10, ..RateLimit::default() },
+        );
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        assert!(toml::from_str::<Config>("[gift]\nlifetim = 60").is_err());
+        assert!(toml::from_str::<Config>("[gifts]").is_err());
+    }
+
+    #[test]
+    fn test_insecure_cross_site_cookie() {
+        assert!(Config::parse("[gift.cookie]\nsame_site = \"None\"").is_ok());
+        assert!(Config::parse("[gift.cookie]\nsame_site = \"None\"\nsecure = false").is_err());
+        assert!(Config::parse("[gift.cookie]\nsame_site = \"Lax\"\nsecure = false").is_ok());
+    }
+}
@@ -1,256 +1,939 @@
-use std::{fmt, iter};
+use std::{sync::Arc, time::{Duration, Instant}};
 
-use axum::{extract::{Path, State}, http::StatusCode, response::IntoResponse};
+use askama::Template;
+use axum::{extract::{Path, Query, State}, http::StatusCode, response::{Html, IntoResponse}, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{prelude::FromRow, query, query_as, query_scalar, types::{Json as SqlJson, Uuid}, PgConnection, PgPool};
+use tokio::sync::{Mutex, MutexGuard, RwLock};
+
+use shuttlings_cch24::{board, solver};
 
 use crate::AppState;
 
-const COOKIE: &str = "cookie";
+use board::{Board, Roster, Team, TeamSpec};
+use solver::{Outcome, Solver};
 
-const MILK: &str = "milk";
+const N: usize = 4;
 
-const WALL: char = '⬜';
+// How far a single game can move a player's Elo rating.
+const ELO_K: f64 = 32.0;
 
-const N: usize = 4;
+const LEADERBOARD_PAGE_SIZE: i64 = 10;
 
-const NO_WINNER: &str = "No winner.\n";
+const LEADERBOARD_MAX_PAGE_SIZE: i64 = 100;
 
-const WINS: &str = " wins!\n";
+const ANALYSIS_MAX_MILLIS: u64 = 5_000;
 
-#[derive(Clone, Copy, PartialEq, Eq, Debug)]
-pub(super) enum Team {
-    Empty,
-    Cookie,
-    Milk,
+// A game in progress. Every move is written through to the `moves` table, so
+// the board can be rebuilt from the database after a restart.
+#[derive(Clone)]
+pub(super) struct Game {
+    id: Uuid,
+    board: Board<N>,
+    moves: i32,
 }
 
-impl Default for Team {
-    fn default() -> Self {
-        Team::Empty
-    }
+#[derive(FromRow)]
+struct Move {
+    team: String,
+    col: i32,
 }
 
-impl Into<char> for &Team {
-    fn into(self) -> char {
-        match self {
-            Team::Empty => '⬛',
-            Team::Cookie=> '🍪',
-            Team::Milk=> '🥛',
-        }
-    }
-}
+impl Game {
+    async fn create(pool: &PgPool, roster: Arc<Roster>) -> Result<Self, sqlx::Error> {
+        let id = Uuid::new_v4();
 
-impl fmt::Display for Team {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let c: char = self.into();
-        write!(f, "{}", c)
+        query("INSERT INTO games (id, teams) VALUES ($1, $2);")
+            .bind(id)
+            .bind(SqlJson(roster.specs()))
+            .execute(pool)
+            .await?;
+
+        Ok(Game { id, board: Board::with_roster(roster), moves: 0 })
     }
-}
 
-pub(super) struct Board<const N: usize> {
-    tiles: Vec<Vec<Team>>,
-}
+    // Replays the moves of the given game onto an empty board. Returns None
+    // if the game does not exist.
+    async fn load(pool: &PgPool, id: Uuid) -> Result<Option<Self>, sqlx::Error> {
+        let roster = match load_roster(pool, id).await? {
+            Some(roster) => roster,
+            None => return Ok(None),
+        };
 
-impl<const N: usize> Board<N> {
-    pub(super) fn new() -> Self {
-        Board {
-            tiles: iter::repeat(
-                iter::repeat(Team::default()).take(N).collect::<Vec<_>>()
-            )
-            .take(N)
-            .collect::<Vec<_>>(),
-        }
-    }
+        let moves = query_as::<_, Move>(r#"
+SELECT team, col FROM moves WHERE game_id = $1 ORDER BY seq ASC;
+        "#)
+        .bind(id)
+        .fetch_all(pool)
+        .await?;
+
+        let mut game = Game { id, board: Board::with_roster(roster), moves: 0 };
 
-    fn reset(&mut self) {
-        for row in 0..self.tiles.len() {
-            for col in 0..self.tiles[0].len() {
-                self.tiles[row][col] = Team::default();
+        for m in moves {
+            if let Some(team) = game.board.roster().team(&m.team) {
+                let _ = game.board.place(m.col as usize, team);
             }
+
+            game.moves += 1;
         }
+
+        Ok(Some(game))
     }
 
-    // N=4 for example:
-    //
-    // col 1             col 4
-    // vvvvv             vvvvv
-    // (0,0) (0,1) (0,2) (0,3)
-    // (1,0) (1,1) (1,2) (1,3)
-    // (2,0) (2,1) (2,2) (2,3)
-    // (3,0) (3,1) (3,2) (3,3)
-    fn place(&mut self, col: usize, tile: Team) -> Result<(), &'static str> {
-        if col < 1 || col > N {
-            return Err("meheh");
-        }
+    // Places the item and records the move. The in-memory board is only
+    // updated once the move has been stored.
+    async fn place(
+        &mut self,
+        pool: &PgPool,
+        col: usize,
+        tile: Team,
+    ) -> Result<Result<(), &'static str>, sqlx::Error> {
+        let mut board = self.board.clone();
 
-        for i in (0..N).rev() {
-            match self.tiles[i][col - 1] {
-                Team::Empty => {
-                    self.tiles[i][col - 1] = tile;
-                    return Ok(())
-                },
-                _ => {},
-            }
+        if let Err(e) = board.place(col, tile) {
+            return Ok(Err(e));
         }
 
-        Err("meh")
-    }
+        let mut tx = pool.begin().await?;
 
-    // Returns Some(winner) if a winner exists. This function never returns
-    // Some(Team::default()).
-    fn winner(&self) -> Option<Team> {
-        // Scan rows
-        for i in 0..N {
-            let maybe_winner = (0..N).fold(self.tiles[i][0], |acc, j| {
-                if acc == self.tiles[i][j] { acc } else { Team::default() }
-            });
+        query(r#"
+INSERT INTO moves (game_id, seq, team, col) VALUES ($1, $2, $3, $4);
+        "#)
+        .bind(self.id)
+        .bind(self.moves + 1)
+        .bind(board.roster().name(tile))
+        .bind(col as i32)
+        .execute(&mut *tx)
+        .await?;
 
-            if matches!(maybe_winner, Team::Cookie | Team::Milk) {
-                return Some(maybe_winner);
-            }
+        if board.over() {
+            query(r#"
+UPDATE games SET finished_at = CURRENT_TIMESTAMP, winner = $1 WHERE id = $2;
+            "#)
+            .bind(board.winner().map(|t| board.roster().name(t)))
+            .bind(self.id)
+            .execute(&mut *tx)
+            .await?;
+
+            rate_players(&mut tx, self.id, &board).await?;
         }
 
-        // Scan cols
-        for i in 0..N {
-            let maybe_winner = (0..N).fold(self.tiles[0][i], |acc, j| {
-                if acc == self.tiles[j][i] { acc } else { Team::default() }
-            });
+        tx.commit().await?;
 
-            if matches!(maybe_winner, Team::Cookie | Team::Milk) {
-                return Some(maybe_winner);
-            }
-        }
+        self.board = board;
+        self.moves += 1;
 
-        // Scan diagonal lines
-        let maybe_winner = (0..N).fold(self.tiles[0][0], |acc, i| {
-            if acc == self.tiles[i][i] { acc } else { Team::default() }
-        });
+        Ok(Ok(()))
+    }
+}
 
-        if matches!(maybe_winner, Team::Cookie | Team::Milk) {
-            return Some(maybe_winner);
-        }
+// Returns the teams of the given game, or None if the game does not exist.
+// Games stored before teams could be chosen are played by cookie and milk.
+async fn load_roster(pool: &PgPool, id: Uuid) -> Result<Option<Arc<Roster>>, sqlx::Error> {
+    let teams = query_scalar::<_, Option<SqlJson<Vec<TeamSpec>>>>(r#"
+SELECT teams FROM games WHERE id = $1;
+    "#)
+    .bind(id)
+    .fetch_optional(pool)
+    .await?;
 
-        let maybe_winner = (0..N).fold(self.tiles[0][N - 1], |acc, i| {
-            if acc == self.tiles[i][N - 1 - i] { acc } else { Team::default() }
-        });
+    Ok(teams.map(|teams| Arc::new(
+        teams
+            .and_then(|SqlJson(specs)| Roster::try_from(specs).ok())
+            .unwrap_or_default()
+    )))
+}
+
+// The most recent game, rebuilt from the database. If there is none, a new
+// game is started.
+async fn load_latest_game(pool: &PgPool) -> Result<Game, sqlx::Error> {
+    let latest = query_scalar::<_, Uuid>(r#"
+SELECT id FROM games ORDER BY created_at DESC LIMIT 1;
+    "#)
+    .fetch_optional(pool)
+    .await?;
+
+    let loaded = match latest {
+        Some(id) => Game::load(pool, id).await?,
+        None => None,
+    };
 
-        if matches!(maybe_winner, Team::Cookie | Team::Milk) {
-            return Some(maybe_winner);
+    match loaded {
+        Some(loaded) => Ok(loaded),
+        None => Game::create(pool, Arc::new(Roster::default())).await,
+    }
+}
+
+// The game being played. Handlers work on a copy of it, so that the lock on
+// it is never held across a query: only to copy it, or to swap in a new one.
+// Those changing it take turns instead, for as long as they need.
+#[derive(Default)]
+pub(super) struct CurrentGame {
+    game: RwLock<Option<Game>>,
+    turn: Mutex<()>,
+}
+
+impl CurrentGame {
+    // A copy of the current game. After a restart, the most recent game is
+    // rebuilt from the database on first access.
+    async fn get(&self, pool: &PgPool) -> Result<Game, sqlx::Error> {
+        if let Some(game) = self.game.read().await.as_ref() {
+            return Ok(game.clone());
         }
 
-        None
+        let _turn = self.turn().await;
+
+        self.load(pool).await
     }
 
-    fn full(&self) -> bool {
-        self.tiles.iter().all(|col| col.iter().all(|&t| t != Team::default()))
+    // Wait for the others to be done changing the game, and keep them waiting
+    // until the guard is dropped.
+    async fn turn(&self) -> MutexGuard<'_, ()> {
+        self.turn.lock().await
     }
 
-    fn winner_alert(&self) -> String {
-        if let Some(t) = self.winner() {
-            format!("{}{}", t, WINS)
-        } else if self.full() {
-            format!("{}", NO_WINNER)
-        } else {
-            String::new()
+    // Same as get, during a turn.
+    async fn load(&self, pool: &PgPool) -> Result<Game, sqlx::Error> {
+        if let Some(game) = self.game.read().await.as_ref() {
+            return Ok(game.clone());
         }
+
+        let game = load_latest_game(pool).await?;
+
+        self.set(&game).await;
+
+        Ok(game)
+    }
+
+    // Make the game current, during a turn.
+    async fn set(&self, game: &Game) {
+        *self.game.write().await = Some(game.clone());
     }
 }
 
-impl<const N: usize> fmt::Display for Board<N> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
-        for row in 0..N {
-            write!(f, "{}", WALL)?;
+async fn current_game(state: &AppState) -> Result<Game, sqlx::Error> {
+    state.game.get(&state.pool).await
+}
 
-            for col in 0..N {
-                write!(f, "{}", self.tiles[row][col])?;
-            }
+// Replaces the current game with a new one. Without a roster, the teams of
+// the current game play again.
+async fn new_game(state: &AppState, roster: Option<Arc<Roster>>) -> Result<Game, sqlx::Error> {
+    let _turn = state.game.turn().await;
 
-            writeln!(f, "{}", WALL)?;
-        }
+    let roster = match roster {
+        Some(roster) => roster,
+        None => state.game.load(&state.pool).await?.board.roster().clone(),
+    };
 
-        for _ in 0..(N + 2) {
-            write!(f, "{}", WALL)?;
-        }
+    let game = Game::create(&state.pool, roster).await?;
 
-        writeln!(f, "")?;
+    state.game.set(&game).await;
 
-        Ok(())
-    }
+    Ok(game)
 }
 
 pub(super) async fn board(
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    let board = state.board.read().await;
+    let game = match current_game(&state).await {
+        Ok(game) => game,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, String::new()),
+    };
 
     (
         StatusCode::OK,
         format!(
             "{}{}",
-            board,
-            board.winner_alert()
+            game.board,
+            game.board.winner_alert()
         ),
     )
 }
 
+// Resetting starts a new game with the same teams. The previous one is kept
+// in the database.
 pub(super) async fn reset(
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    let mut board = state.board.write().await;
+    match new_game(&state, None).await {
+        Ok(game) => (StatusCode::OK, game.board.to_string()),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, String::new()),
+    }
+}
 
-    board.reset();
+#[derive(Deserialize)]
+pub(super) struct NewGame {
+    teams: Option<Vec<TeamSpec>>,
+}
+
+#[derive(Serialize)]
+struct CreatedGame<'a> {
+    id: Uuid,
+    teams: &'a [TeamSpec],
+}
+
+// POST /12/games: Start a new game between 2 to 4 teams, given their names and
+// glyphs, and respond with its ID and 201 Created. Without teams, cookie and
+// milk play. The new game replaces the current one.
+pub(super) async fn create_game(
+    State(state): State<AppState>,
+    Json(new): Json<NewGame>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    let roster = match new.teams.map(Roster::try_from) {
+        Some(Ok(roster)) => roster,
+        Some(Err(e)) => return Err((StatusCode::BAD_REQUEST, String::from(e))),
+        None => Roster::default(),
+    };
 
-    (StatusCode::OK, board.to_string())
+    match new_game(&state, Some(Arc::new(roster))).await {
+        Ok(game) => Ok((
+            StatusCode::CREATED,
+            serde_json::to_string(&CreatedGame {
+                id: game.id,
+                teams: game.board.roster().specs(),
+            }).unwrap(),
+        )),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, String::new())),
+    }
 }
 
 pub(super) async fn place(
     Path((team, column)): Path<(String, usize)>,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    // {team} is either cookie or milk. {column} is a number between 1 and 4.
-    // If either is invalid, return 400 Bad Request (response body does not
-    // matter).
-    if column < 1 || column > N {
+    // {team} is one of the teams of the game, cookie or milk unless other
+    // teams were chosen. {column} is a number between 1 and 4. If either is
+    // invalid, return 400 Bad Request (response body does not matter).
+    if !(1..=N).contains(&column) {
         return (StatusCode::BAD_REQUEST, String::new());
     }
 
-    let tile = if &team == COOKIE {
-        Team::Cookie
-    } else if team == MILK {
-        Team::Milk
+    let _turn = state.game.turn().await;
+
+    let mut game = match state.game.load(&state.pool).await {
+        Ok(game) => game,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, String::new()),
+    };
+
+    let tile = if let Some(tile) = game.board.roster().team(&team) {
+        tile
     } else {
         return (StatusCode::BAD_REQUEST, String::new());
     };
 
-    let mut board = state.board.write().await;
-
     // If the game is over (has a winner or no winner), return the board
     // with a 503 Service Unavailable status.
-    if let Some(t) = board.winner() {
-        return (
-            StatusCode::SERVICE_UNAVAILABLE,
-            format!("{}{}{}", board, t, WINS)
-        );
-    } else if board.full() {
+    if game.board.over() {
         return (
             StatusCode::SERVICE_UNAVAILABLE,
-            format!("{}{}", board, NO_WINNER),
+            format!("{}{}", game.board, game.board.winner_alert()),
         );
     }
 
     // The endpoint should place the incoming item by letting it fall down the
     // column and land in the lowest empty tile. After the new item has been
     // placed, return the board with a 200 OK status.
-    if let Ok(_) = board.place(column, tile) {
-        let msg = board.winner_alert();
+    match game.place(&state.pool, column, tile).await {
+        Ok(Ok(_)) => {
+            state.game.set(&game).await;
 
-        (StatusCode::OK, format!("{}{}", board, msg))
-    } else {
+            let msg = game.board.winner_alert();
+
+            (StatusCode::OK, format!("{}{}", game.board, msg))
+        },
         // If the column requested is already full, return the board with
         // a 503 Service Unavailable status.
-        (
+        Ok(Err(_)) => (
             StatusCode::SERVICE_UNAVAILABLE,
-            format!("{}", board),
-        )
+            format!("{}", game.board),
+        ),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, String::new()),
+    }
+}
+
+#[derive(Serialize)]
+struct ReplayStep {
+    seq: i32,
+    team: String,
+    glyph: String,
+    column: i32,
+    board: String,
+}
+
+// GET /12/games/{id}/replay: Respond with the board after each move of the
+// given game. Use 404 Not Found if the game does not exist.
+pub(super) async fn replay(
+    Path(id): Path<Uuid>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    let roster = match load_roster(&state.pool, id).await {
+        Ok(Some(roster)) => roster,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let moves = match query_as::<_, Move>(r#"
+SELECT team, col FROM moves WHERE game_id = $1 ORDER BY seq ASC;
+    "#)
+    .bind(id)
+    .fetch_all(&state.pool)
+    .await {
+        Ok(moves) => moves,
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let mut board: Board<N> = Board::with_roster(roster.clone());
+
+    let steps = moves.into_iter()
+        .zip(1..)
+        .map(|(m, seq)| {
+            let team = roster.team(&m.team).unwrap_or_default();
+
+            let _ = board.place(m.col as usize, team);
+
+            ReplayStep {
+                seq,
+                team: m.team,
+                glyph: String::from(roster.glyph(team)),
+                column: m.col,
+                board: board.to_string(),
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok((
+        StatusCode::OK,
+        serde_json::to_string(&steps).unwrap(),
+    ))
+}
+
+// Returns the new Elo ratings of the players of a game, given the index of
+// the winner, or None for a draw. With more than two players, every pair of
+// players counts as a game of its own: the winner beats everyone else, and
+// everyone else draws. K is shared between the opponents of each player.
+fn elo(ratings: &[f64], winner: Option<usize>) -> Vec<f64> {
+    let k = ELO_K / (ratings.len().max(2) - 1) as f64;
+
+    ratings.iter()
+        .enumerate()
+        .map(|(i, &rating)| {
+            rating + ratings.iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .map(|(j, &opponent_rating)| {
+                    let expected = 1.0 / (1.0 + 10f64.powf((opponent_rating - rating) / 400.0));
+                    let score = match winner {
+                        Some(w) if w == i => 1.0,
+                        Some(w) if w == j => 0.0,
+                        _ => 0.5,
+                    };
+
+                    k * (score - expected)
+                })
+                .sum::<f64>()
+        })
+        .collect()
+}
+
+// Updates the ratings and win/loss/draw counts of the players of a finished
+// game. Games where a side has not been taken by a player are not rated.
+async fn rate_players<const N: usize>(
+    conn: &mut PgConnection,
+    game_id: Uuid,
+    board: &Board<N>,
+) -> Result<(), sqlx::Error> {
+    let players = query_as::<_, (String, String, f64)>(r#"
+SELECT gp.team, p.name, p.rating
+FROM game_players gp
+JOIN players p ON p.name = gp.player
+WHERE gp.game_id = $1
+FOR UPDATE OF p;
+    "#)
+    .bind(game_id)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    let roster = board.roster();
+
+    let seats = roster.teams()
+        .filter_map(|team| {
+            players.iter().find(|(t, _, _)| t == roster.name(team))
+        })
+        .collect::<Vec<_>>();
+
+    if seats.len() != roster.specs().len() {
+        return Ok(());
+    }
+
+    let winner = board.winner().map(|team| roster.name(team));
+    let ratings = elo(
+        &seats.iter().map(|&(_, _, rating)| *rating).collect::<Vec<_>>(),
+        seats.iter().position(|(team, _, _)| Some(team.as_str()) == winner),
+    );
+
+    for ((team, name, _), rating) in seats.into_iter().zip(ratings) {
+        query(r#"
+UPDATE players
+SET
+    rating = $1,
+    wins = wins + $2,
+    losses = losses + $3,
+    draws = draws + $4
+WHERE name = $5;
+        "#)
+        .bind(rating)
+        .bind((winner == Some(team.as_str())) as i32)
+        .bind((winner.is_some() && winner != Some(team.as_str())) as i32)
+        .bind(winner.is_none() as i32)
+        .bind(name)
+        .execute(&mut *conn)
+        .await?;
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Seat {
+    game: Uuid,
+    team: String,
+    player: String,
+}
+
+// POST /12/join/{team}/{player}: Take a side of the current game under a
+// player name. Use 409 Conflict if the side is already taken by someone else,
+// or the player already plays another side.
+pub(super) async fn join(
+    Path((team, player)): Path<(String, String)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    if player.trim().is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let game = match current_game(&state).await {
+        Ok(game) => game,
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    if game.board.roster().team(&team).is_none() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    if game.board.over() {
+        return Err(StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    let seats = match query_as::<_, (String, String)>(r#"
+SELECT team, player FROM game_players WHERE game_id = $1;
+    "#)
+    .bind(game.id)
+    .fetch_all(&state.pool)
+    .await {
+        Ok(seats) => seats,
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    for (t, p) in &seats {
+        if (*t == team) != (*p == player) {
+            return Err(StatusCode::CONFLICT);
+        }
+    }
+
+    let seat = Seat { game: game.id, team, player };
+
+    if seats.iter().all(|(t, _)| *t != seat.team) {
+        let result = async {
+            let mut tx = state.pool.begin().await?;
+
+            query("INSERT INTO players (name) VALUES ($1) ON CONFLICT DO NOTHING;")
+                .bind(&seat.player)
+                .execute(&mut *tx)
+                .await?;
+
+            query(r#"
+INSERT INTO game_players (game_id, team, player) VALUES ($1, $2, $3);
+            "#)
+            .bind(seat.game)
+            .bind(&seat.team)
+            .bind(&seat.player)
+            .execute(&mut *tx)
+            .await?;
+
+            tx.commit().await
+        }.await;
+
+        if result.is_err() {
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+
+    Ok((
+        StatusCode::OK,
+        serde_json::to_string(&seat).unwrap(),
+    ))
+}
+
+#[derive(Deserialize)]
+pub(super) struct LeaderboardParams {
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+#[derive(Serialize, FromRow)]
+struct Standing {
+    rank: i64,
+    name: String,
+    rating: i32,
+    wins: i32,
+    losses: i32,
+    draws: i32,
+}
+
+#[derive(Serialize)]
+struct Leaderboard {
+    players: Vec<Standing>,
+    page: i64,
+    per_page: i64,
+    total: i64,
+}
+
+// The players to skip before the page, or None if the page or its size is out
+// of range, as the offset of a far enough page would be.
+fn leaderboard_offset(page: i64, per_page: i64) -> Option<i64> {
+    if page < 1 || !(1..=LEADERBOARD_MAX_PAGE_SIZE).contains(&per_page) {
+        return None;
+    }
+
+    (page - 1).checked_mul(per_page)
+}
+
+// GET /12/leaderboard?page={page}&per_page={per_page}: Respond with the
+// players ordered by their Elo rating.
+pub(super) async fn leaderboard(
+    State(state): State<AppState>,
+    Query(params): Query<LeaderboardParams>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    let page = params.page.unwrap_or(1);
+    let per_page = params.per_page.unwrap_or(LEADERBOARD_PAGE_SIZE);
+
+    let offset = match leaderboard_offset(page, per_page) {
+        Some(offset) => offset,
+        None => return Err(StatusCode::BAD_REQUEST),
+    };
+
+    let total = match query_scalar::<_, i64>("SELECT COUNT(*) FROM players;")
+        .fetch_one(&state.pool)
+        .await {
+        Ok(total) => total,
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    match query_as::<_, Standing>(r#"
+SELECT
+    RANK() OVER (ORDER BY rating DESC) AS rank,
+    name,
+    ROUND(rating)::INT AS rating,
+    wins,
+    losses,
+    draws
+FROM players
+ORDER BY rating DESC, name ASC
+LIMIT $1 OFFSET $2;
+    "#)
+    .bind(per_page)
+    .bind(offset)
+    .fetch_all(&state.pool)
+    .await {
+        Ok(players) => Ok((
+            StatusCode::OK,
+            serde_json::to_string(&Leaderboard { players, page, per_page, total }).unwrap(),
+        )),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[derive(Serialize, FromRow)]
+struct HeadToHead {
+    opponent: String,
+    wins: i64,
+    losses: i64,
+    draws: i64,
+}
+
+#[derive(Serialize)]
+struct PlayerStats {
+    name: String,
+    rating: i32,
+    wins: i32,
+    losses: i32,
+    draws: i32,
+    head_to_head: Vec<HeadToHead>,
+}
+
+// GET /12/leaderboard/{player}: Respond with the player's rating and their
+// record against each opponent. Use 404 Not Found if the player is unknown.
+pub(super) async fn player_stats(
+    Path(player): Path<String>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    let standing = match query_as::<_, Standing>(r#"
+SELECT 0::BIGINT AS rank, name, ROUND(rating)::INT AS rating, wins, losses, draws
+FROM players
+WHERE name = $1;
+    "#)
+    .bind(&player)
+    .fetch_optional(&state.pool)
+    .await {
+        Ok(Some(standing)) => standing,
+        Ok(None) => return Err(StatusCode::NOT_FOUND),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    match query_as::<_, HeadToHead>(r#"
+SELECT
+    o.player AS opponent,
+    COUNT(*) FILTER (WHERE g.winner = p.team) AS wins,
+    COUNT(*) FILTER (WHERE g.winner = o.team) AS losses,
+    COUNT(*) FILTER (WHERE g.winner IS NULL) AS draws
+FROM game_players p
+JOIN game_players o ON o.game_id = p.game_id AND o.team <> p.team
+JOIN games g ON g.id = p.game_id
+WHERE p.player = $1 AND g.finished_at IS NOT NULL
+GROUP BY o.player
+ORDER BY o.player ASC;
+    "#)
+    .bind(&player)
+    .fetch_all(&state.pool)
+    .await {
+        Ok(head_to_head) => Ok((
+            StatusCode::OK,
+            serde_json::to_string(&PlayerStats {
+                name: standing.name,
+                rating: standing.rating,
+                wins: standing.wins,
+                losses: standing.losses,
+                draws: standing.draws,
+                head_to_head,
+            }).unwrap(),
+        )),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[derive(Deserialize)]
+struct AnalyzedMove {
+    team: String,
+    column: usize,
+}
+
+#[derive(Deserialize)]
+pub(super) struct Analyze {
+    moves: Option<Vec<AnalyzedMove>>,
+    to_move: Option<String>,
+    max_nodes: Option<u64>,
+    max_millis: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ColumnAnalysis {
+    column: usize,
+    // None if the budget ran out before the column was solved.
+    result: Option<Outcome>,
+    plies: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct Analysis {
+    to_move: String,
+    complete: bool,
+    nodes: u64,
+    columns: Vec<ColumnAnalysis>,
+}
+
+// POST /12/analyze: Respond with the result of every legal move under perfect
+// play by both teams, that is win, draw or loss for the team to move and the
+// number of moves until the game ends. The position is given by the moves
+// that lead to it from the empty board between cookie and milk, or else is
+// the one of the current game. Teams are assumed to take turns, the team to
+// move being the one with the fewest items unless told otherwise.
+//
+// The search stops after `max_nodes` positions or `max_millis` milliseconds,
+// and the columns it could not solve by then have no result.
+pub(super) async fn analyze(
+    State(state): State<AppState>,
+    Json(analyze): Json<Analyze>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    let board = if let Some(moves) = analyze.moves {
+        let mut board: Board<N> = Board::new();
+
+        for m in moves {
+            let team = match board.roster().team(&m.team) {
+                Some(team) => team,
+                None => return Err((StatusCode::BAD_REQUEST, String::from("unknown team"))),
+            };
+
+            if board.over() || board.place(m.column, team).is_err() {
+                return Err((StatusCode::BAD_REQUEST, String::from("illegal move")));
+            }
+        }
+
+        board
+    } else {
+        match current_game(&state).await {
+            Ok(game) => game.board.clone(),
+            Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, String::new())),
+        }
+    };
+
+    let roster = board.roster().clone();
+
+    if roster.specs().len() != 2 {
+        return Err((StatusCode::BAD_REQUEST, String::from("analysis needs exactly two teams")));
+    }
+
+    let mover = match analyze.to_move {
+        Some(name) => match roster.team(&name) {
+            Some(team) => team,
+            None => return Err((StatusCode::BAD_REQUEST, String::from("unknown team"))),
+        },
+        None => board.next_team(),
+    };
+
+    let other = roster.teams().find(|&team| team != mover).unwrap();
+
+    let max_nodes = analyze.max_nodes.unwrap_or(solver::MAX_NODES).min(solver::MAX_NODES);
+    let max_millis = analyze.max_millis.unwrap_or(ANALYSIS_MAX_MILLIS).min(ANALYSIS_MAX_MILLIS);
+
+    // The search is CPU-bound, so keep it off the async workers.
+    let analysis = tokio::task::spawn_blocking(move || {
+        let mut solver = Solver::new(max_nodes, Instant::now() + Duration::from_millis(max_millis));
+        let mut columns = Vec::new();
+
+        if !board.over() {
+            for column in 1..=N {
+                match solver.solve(&board, column, mover, other) {
+                    Ok(Some(verdict)) => columns.push(ColumnAnalysis {
+                        column,
+                        result: Some(verdict.outcome),
+                        plies: Some(verdict.plies),
+                    }),
+                    Ok(None) => {},
+                    Err(_) => columns.push(ColumnAnalysis { column, result: None, plies: None }),
+                }
+            }
+        }
+
+        Analysis {
+            to_move: String::from(roster.name(mover)),
+            complete: columns.iter().all(|c| c.result.is_some()),
+            nodes: solver.nodes(),
+            columns,
+        }
+    }).await;
+
+    match analysis {
+        Ok(analysis) => Ok((StatusCode::OK, serde_json::to_string(&analysis).unwrap())),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, String::new())),
+    }
+}
+
+// The board as a clickable HTML fragment. Each column posts the next team's
+// item to /12/play/place and the response replaces the whole fragment.
+#[derive(Template)]
+#[template(path = "../templates/board.html")]
+struct BoardFragment {
+    columns: Vec<Vec<String>>,
+    next_team: String,
+    next_glyph: String,
+    alert: String,
+    over: bool,
+}
+
+impl<const N: usize> From<&Board<N>> for BoardFragment {
+    fn from(board: &Board<N>) -> Self {
+        let roster = board.roster();
+        let next_team = board.next_team();
+
+        BoardFragment {
+            columns: (0..N)
+                .map(|col| {
+                    (0..N)
+                        .map(|row| String::from(roster.glyph(board.tile(row, col))))
+                        .collect()
+                })
+                .collect(),
+            next_team: String::from(roster.name(next_team)),
+            next_glyph: String::from(roster.glyph(next_team)),
+            alert: board.winner_alert(),
+            over: board.over(),
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "../templates/play.html")]
+struct PlayPage {
+    board: BoardFragment,
+}
+
+// GET /12/play: Render the board as a page that can be played in a browser.
+pub(super) async fn play(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    match current_game(&state).await {
+        Ok(game) => {
+            let page = PlayPage { board: BoardFragment::from(&game.board) };
+
+            Ok(Html::from(page.render().unwrap()))
+        },
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+// POST /12/play/place/{team}/{column}: Same as /12/place, but respond with
+// the re-rendered board fragment.
+pub(super) async fn play_place(
+    Path((team, column)): Path<(String, usize)>,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    if !(1..=N).contains(&column) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let _turn = state.game.turn().await;
+
+    let mut game = match state.game.load(&state.pool).await {
+        Ok(game) => game,
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+
+    let tile = if let Some(tile) = game.board.roster().team(&team) {
+        tile
+    } else {
+        return Err(StatusCode::BAD_REQUEST);
+    };
+
+    // Clicking a full column or a finished board simply shows the board again.
+    if !game.board.over() {
+        match game.place(&state.pool, column, tile).await {
+            Ok(Ok(())) => state.game.set(&game).await,
+            Ok(Err(_)) => {},
+            Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+        }
+    }
+
+    Ok(Html::from(BoardFragment::from(&game.board).render().unwrap()))
+}
+
+// POST /12/play/reset: Same as /12/reset, but respond with the re-rendered
+// board fragment.
+pub(super) async fn play_reset(
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    match new_game(&state, None).await {
+        Ok(game) => Ok(Html::from(BoardFragment::from(&game.board).render().unwrap())),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
     }
 }
 
@@ -280,16 +963,16 @@ const EMPTY_BOARD: &str = "\
     fn test_mini() {
         let mut board: Board<4> = Board::new();
 
-        let _ = board.place(1, Team::Cookie);
-        let _ = board.place(2, Team::Milk);
-        let _ = board.place(2, Team::Cookie);
-        let _ = board.place(3, Team::Milk);
-        let _ = board.place(3, Team::Milk);
-        let _ = board.place(3, Team::Cookie);
-        let _ = board.place(4, Team::Milk);
-        let _ = board.place(4, Team::Milk);
-        let _ = board.place(4, Team::Milk);
-        let _ = board.place(4, Team::Cookie);
+        let _ = board.place(1, Team::COOKIE);
+        let _ = board.place(2, Team::MILK);
+        let _ = board.place(2, Team::COOKIE);
+        let _ = board.place(3, Team::MILK);
+        let _ = board.place(3, Team::MILK);
+        let _ = board.place(3, Team::COOKIE);
+        let _ = board.place(4, Team::MILK);
+        let _ = board.place(4, Team::MILK);
+        let _ = board.place(4, Team::MILK);
+        let _ = board.place(4, Team::COOKIE);
 
         assert_eq!(
             board.to_string(),
@@ -302,6 +985,112 @@ const EMPTY_BOARD: &str = "\
 "
         ));
 
-        assert_eq!(board.winner(), Some(Team::Cookie));
+        assert_eq!(board.winner(), Some(Team::COOKIE));
+    }
+
+    #[test]
+    fn test_board_fragment() {
+        let mut board: Board<4> = Board::new();
+
+        let _ = board.place(2, Team::COOKIE);
+
+        let html = BoardFragment::from(&board).render().unwrap();
+
+        assert!(html.contains("hx-post=\"/12/play/place/milk/1\""));
+        assert!(html.contains("hx-post=\"/12/play/place/milk/4\""));
+        assert_eq!(html.matches("🍪").count(), 1);
+
+        for col in 1..=4 {
+            for _ in 0..4 {
+                let _ = board.place(col, Team::MILK);
+            }
+        }
+
+        let html = BoardFragment::from(&board).render().unwrap();
+
+        assert!(!html.contains("/12/play/place/"));
+        assert!(html.contains(" wins!"));
+    }
+
+    #[test]
+    fn test_elo() {
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+
+        let ratings = elo(&[1200.0, 1200.0], Some(0));
+
+        assert!(close(ratings[0], 1216.0) && close(ratings[1], 1184.0));
+
+        let ratings = elo(&[1000.0, 1400.0], None);
+
+        assert!(ratings[0] > 1000.0 && ratings[1] < 1400.0);
+        assert!(close(ratings[0] + ratings[1], 2400.0));
+
+        let ratings = elo(&[1200.0, 1200.0, 1200.0, 1200.0], Some(2));
+
+        assert!(close(ratings[2], 1216.0));
+        assert!(close(ratings[0], ratings[1]) && close(ratings[1], ratings[3]));
+        assert!(close(ratings.iter().sum::<f64>(), 4800.0));
+    }
+
+    #[test]
+    fn test_custom_teams() {
+        let roster = Roster::try_from(vec![
+            TeamSpec { name: String::from("cookie"), glyph: String::from("🍪") },
+            TeamSpec { name: String::from("milk"), glyph: String::from("🥛") },
+            TeamSpec { name: String::from("santa"), glyph: String::from("🎅") },
+        ]).unwrap();
+
+        let santa = roster.team("santa").unwrap();
+        let mut board: Board<4> = Board::with_roster(Arc::new(roster));
+
+        let _ = board.place(1, Team::COOKIE);
+        let _ = board.place(1, Team::MILK);
+
+        assert_eq!(board.next_team(), santa);
+
+        for _ in 0..4 {
+            let _ = board.place(4, santa);
+        }
+
+        assert_eq!(board.winner(), Some(santa));
+        assert_eq!(board.winner_alert(), "🎅 wins!\n");
+        assert_eq!(board.to_string(), String::from("\
+⬜⬛⬛⬛🎅⬜
+⬜⬛⬛⬛🎅⬜
+⬜🥛⬛⬛🎅⬜
+⬜🍪⬛⬛🎅⬜
+⬜⬜⬜⬜⬜⬜
+"));
+    }
+
+    #[test]
+    fn test_roster() {
+        let spec = |name: &str, glyph: &str| TeamSpec {
+            name: String::from(name),
+            glyph: String::from(glyph),
+        };
+
+        assert!(Roster::try_from(vec![spec("cookie", "🍪")]).is_err());
+        assert!(Roster::try_from(vec![spec("cookie", "🍪"), spec("milk", "🍪")]).is_err());
+        assert!(Roster::try_from(vec![spec("cookie", "🍪"), spec("cookie", "🥛")]).is_err());
+        assert!(Roster::try_from(vec![spec("cookie", "🍪"), spec("mi/lk", "🥛")]).is_err());
+        assert!(Roster::try_from(vec![spec("cookie", "🍪"), spec("milk", "⬛")]).is_err());
+        assert!(Roster::try_from(vec![
+            spec("a", "🍪"), spec("b", "🥛"), spec("c", "🎅"), spec("d", "🦌"), spec("e", "⛄"),
+        ]).is_err());
+        assert!(Roster::try_from(vec![
+            spec("a", "🍪"), spec("b", "🥛"), spec("c", "🎅"), spec("d", "🦌"),
+        ]).is_ok());
+    }
+
+    #[test]
+    fn test_leaderboard_offset() {
+        assert_eq!(leaderboard_offset(1, 10), Some(0));
+        assert_eq!(leaderboard_offset(3, 25), Some(50));
+        assert_eq!(leaderboard_offset(0, 10), None);
+        assert_eq!(leaderboard_offset(-1, 10), None);
+        assert_eq!(leaderboard_offset(1, 0), None);
+        assert_eq!(leaderboard_offset(1, LEADERBOARD_MAX_PAGE_SIZE + 1), None);
+        assert_eq!(leaderboard_offset(i64::MAX, 10), None);
     }
 }
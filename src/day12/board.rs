@@ -0,0 +1,423 @@
+use std::{fmt, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+
+const COOKIE: &str = "cookie";
+
+const MILK: &str = "milk";
+
+const EMPTY: &str = "⬛";
+
+const WALL: &str = "⬜";
+
+const NO_WINNER: &str = "No winner.\n";
+
+const WINS: &str = " wins!\n";
+
+const MAX_TEAMS: usize = 4;
+
+const MAX_NAME_LEN: usize = 16;
+
+const MAX_GLYPH_LEN: usize = 4;
+
+// A team is identified by its position in the roster of the game, starting
+// from 1. Team::EMPTY stands for an empty tile.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct Team(u8);
+
+impl Team {
+    pub const EMPTY: Team = Team(0);
+
+    // The teams of the default roster.
+    pub const COOKIE: Team = Team(1);
+
+    pub const MILK: Team = Team(2);
+
+    fn index(&self) -> usize {
+        self.0 as usize - 1
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub struct TeamSpec {
+    pub name: String,
+    pub glyph: String,
+}
+
+// The teams taking part in a game, between 2 and 4 of them.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Roster(Vec<TeamSpec>);
+
+impl Default for Roster {
+    fn default() -> Self {
+        Roster(vec![
+            TeamSpec { name: String::from(COOKIE), glyph: String::from("🍪") },
+            TeamSpec { name: String::from(MILK), glyph: String::from("🥛") },
+        ])
+    }
+}
+
+impl TryFrom<Vec<TeamSpec>> for Roster {
+    type Error = &'static str;
+
+    fn try_from(specs: Vec<TeamSpec>) -> Result<Self, Self::Error> {
+        if !(2..=MAX_TEAMS).contains(&specs.len()) {
+            return Err("a game needs 2 to 4 teams");
+        }
+
+        for (i, spec) in specs.iter().enumerate() {
+            // Team names end up in URLs such as /12/place/{team}/{column}.
+            if spec.name.is_empty()
+                || spec.name.len() > MAX_NAME_LEN
+                || !spec.name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+                return Err("bad team name");
+            }
+
+            if spec.glyph.is_empty()
+                || spec.glyph.chars().count() > MAX_GLYPH_LEN
+                || spec.glyph.chars().any(char::is_whitespace)
+                || spec.glyph == EMPTY
+                || spec.glyph == WALL {
+                return Err("bad team glyph");
+            }
+
+            if specs[..i].iter().any(|other| other.name == spec.name || other.glyph == spec.glyph) {
+                return Err("duplicate team");
+            }
+        }
+
+        Ok(Roster(specs))
+    }
+}
+
+impl Roster {
+    pub fn specs(&self) -> &[TeamSpec] {
+        &self.0
+    }
+
+    pub fn teams(&self) -> impl Iterator<Item = Team> {
+        (1..=self.0.len() as u8).map(Team)
+    }
+
+    pub fn contains(&self, team: Team) -> bool {
+        team != Team::EMPTY && team.index() < self.0.len()
+    }
+
+    pub fn team(&self, name: &str) -> Option<Team> {
+        self.teams().find(|&team| self.name(team) == name)
+    }
+
+    pub fn name(&self, team: Team) -> &str {
+        if self.contains(team) { &self.0[team.index()].name } else { "" }
+    }
+
+    pub fn glyph(&self, team: Team) -> &str {
+        if self.contains(team) { &self.0[team.index()].glyph } else { EMPTY }
+    }
+}
+
+// The board is kept as one bitboard per team. Each column takes N + 1 bits,
+// with the bottom tile in the lowest bit. The extra bit on top of every
+// column is always zero, so that runs of tiles never wrap into the next
+// column when a bitboard is shifted. N=4 for example:
+//
+// col 1          col 4
+// vvvvv          vvvvv
+//   4    9   14   19    <- always zero
+//   3    8   13   18
+//   2    7   12   17
+//   1    6   11   16
+//   0    5   10   15
+#[derive(Clone)]
+pub struct Board<const N: usize> {
+    tiles: [u64; MAX_TEAMS],
+    roster: Arc<Roster>,
+}
+
+// Line-start bits of the runs of N tiles in each direction.
+#[derive(Clone, Copy)]
+struct Lines {
+    rows: u64,
+    cols: u64,
+    diagonal: u64,
+    anti_diagonal: u64,
+}
+
+impl<const N: usize> Board<N> {
+    const HEIGHT: usize = N + 1;
+
+    const FITS: () = assert!(N * (N + 1) <= 64, "the board does not fit in a u64");
+
+    const COLUMN: u64 = (1 << N) - 1;
+
+    const ALL: u64 = {
+        let mut all = 0;
+        let mut col = 0;
+
+        while col < N {
+            all |= Self::COLUMN << (col * Self::HEIGHT);
+            col += 1;
+        }
+
+        all
+    };
+
+    // A board for the default cookie and milk teams.
+    pub fn new() -> Self {
+        Self::with_roster(Arc::new(Roster::default()))
+    }
+
+    pub fn with_roster(roster: Arc<Roster>) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::FITS;
+
+        Board { tiles: [0; MAX_TEAMS], roster }
+    }
+
+    pub fn roster(&self) -> &Arc<Roster> {
+        &self.roster
+    }
+
+    // The tiles of every team, which tell positions apart.
+    pub fn position(&self) -> [u64; MAX_TEAMS] {
+        self.tiles
+    }
+
+    fn occupied(&self) -> u64 {
+        self.tiles.iter().fold(0, |acc, tiles| acc | tiles)
+    }
+
+    // Returns the tile at the given row and column, where row 0 is the top of
+    // the board.
+    pub fn tile(&self, row: usize, col: usize) -> Team {
+        let bit = 1 << (col * Self::HEIGHT + N - 1 - row);
+
+        self.roster.teams()
+            .find(|team| self.tiles[team.index()] & bit != 0)
+            .unwrap_or(Team::EMPTY)
+    }
+
+    pub fn place(&mut self, col: usize, tile: Team) -> Result<(), &'static str> {
+        if col < 1 || col > N {
+            return Err("meheh");
+        }
+
+        if tile != Team::EMPTY && !self.roster.contains(tile) {
+            return Err("no such team");
+        }
+
+        let shift = (col - 1) * Self::HEIGHT;
+        let height = ((self.occupied() >> shift) & Self::COLUMN).count_ones() as usize;
+
+        if height == N {
+            return Err("meh");
+        }
+
+        if tile != Team::EMPTY {
+            self.tiles[tile.index()] |= 1 << (shift + height);
+        }
+
+        Ok(())
+    }
+
+    fn lines(tiles: u64) -> Lines {
+        let run = |step: usize| {
+            (1..N).fold(tiles, |acc, i| acc & (tiles >> (i * step)))
+        };
+
+        Lines {
+            rows: run(Self::HEIGHT),
+            cols: run(1),
+            diagonal: run(Self::HEIGHT - 1),
+            anti_diagonal: run(Self::HEIGHT + 1),
+        }
+    }
+
+    // Returns Some(winner) if a winner exists. This function never returns
+    // Some(Team::default()).
+    //
+    // Should several teams own a line, the winner is the owner of the first
+    // line found when scanning rows from top to bottom, then columns from left
+    // to right, then the diagonal from the top left corner and finally the one
+    // from the top right corner.
+    pub fn winner(&self) -> Option<Team> {
+        let lines = self.tiles.map(Self::lines);
+        let teams = || self.roster.teams().filter(|team| {
+            let lines = lines[team.index()];
+
+            lines.rows | lines.cols | lines.diagonal | lines.anti_diagonal != 0
+        });
+
+        // A higher row starts at a higher bit, and a column further left
+        // starts at a lower bit.
+        teams().filter(|team| lines[team.index()].rows != 0)
+            .max_by_key(|team| lines[team.index()].rows)
+            .or_else(|| teams().filter(|team| lines[team.index()].cols != 0)
+                .min_by_key(|team| lines[team.index()].cols.trailing_zeros()))
+            .or_else(|| teams().find(|team| lines[team.index()].diagonal != 0))
+            .or_else(|| teams().find(|team| lines[team.index()].anti_diagonal != 0))
+    }
+
+    pub fn full(&self) -> bool {
+        self.occupied() == Self::ALL
+    }
+
+    pub fn over(&self) -> bool {
+        self.winner().is_some() || self.full()
+    }
+
+    // The team whose turn it is when the teams take turns in roster order.
+    pub fn next_team(&self) -> Team {
+        self.roster.teams()
+            .min_by_key(|team| self.tiles[team.index()].count_ones())
+            .unwrap_or(Team::EMPTY)
+    }
+
+    pub fn winner_alert(&self) -> String {
+        if let Some(t) = self.winner() {
+            format!("{}{}", self.roster.glyph(t), WINS)
+        } else if self.full() {
+            String::from(NO_WINNER)
+        } else {
+            String::new()
+        }
+    }
+}
+
+impl<const N: usize> Default for Board<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Display for Board<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        for row in 0..N {
+            write!(f, "{}", WALL)?;
+
+            for col in 0..N {
+                write!(f, "{}", self.roster.glyph(self.tile(row, col)))?;
+            }
+
+            writeln!(f, "{}", WALL)?;
+        }
+
+        for _ in 0..(N + 2) {
+            write!(f, "{}", WALL)?;
+        }
+
+        writeln!(f)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::reference::VecBoard;
+
+    const GLYPHS: [&str; MAX_TEAMS] = ["🍪", "🥛", "🎅", "🦌"];
+
+    fn roster(teams: usize) -> Arc<Roster> {
+        Arc::new(Roster::try_from(
+            (0..teams)
+                .map(|i| TeamSpec { name: format!("team{}", i), glyph: String::from(GLYPHS[i]) })
+                .collect::<Vec<_>>()
+        ).unwrap())
+    }
+
+    // Columns 0 and N + 1 are out of range on purpose, and so is the team
+    // after the last one of the roster.
+    fn game<const N: usize>() -> impl Strategy<Value = (usize, Vec<(usize, Team)>)> {
+        (2..=MAX_TEAMS).prop_flat_map(|teams| (
+            Just(teams),
+            prop::collection::vec(
+                (0..=N + 1, (0..=teams as u8 + 1).prop_map(Team)),
+                0..=N * N * 2,
+            ),
+        ))
+    }
+
+    fn check<const N: usize>(
+        (teams, moves): (usize, Vec<(usize, Team)>),
+    ) -> Result<(), TestCaseError> {
+        let roster = roster(teams);
+        let mut board: Board<N> = Board::with_roster(roster.clone());
+        let mut reference: VecBoard<N> = VecBoard::with_roster(roster.clone());
+
+        for (col, tile) in moves {
+            if tile.0 as usize > teams {
+                prop_assert!(board.place(col, tile).is_err());
+                continue;
+            }
+
+            prop_assert_eq!(board.place(col, tile), reference.place(col, tile));
+            prop_assert_eq!(board.to_string(), reference.to_string());
+            prop_assert_eq!(board.winner(), reference.winner());
+            prop_assert_eq!(board.full(), reference.full());
+        }
+
+        Ok(())
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(512))]
+
+        #[test]
+        fn test_same_as_reference_3(game in game::<3>()) {
+            check::<3>(game)?;
+        }
+
+        #[test]
+        fn test_same_as_reference_4(game in game::<4>()) {
+            check::<4>(game)?;
+        }
+
+        #[test]
+        fn test_same_as_reference_5(game in game::<5>()) {
+            check::<5>(game)?;
+        }
+
+        #[test]
+        fn test_same_as_reference_7(game in game::<7>()) {
+            check::<7>(game)?;
+        }
+    }
+
+    // Every position a 4x4 board can be in.
+    #[test]
+    fn test_exhaustive_4() {
+        const N: usize = 4;
+
+        let columns = (0..=N)
+            .flat_map(|height| (0..1 << height).map(move |bits| {
+                (0..height)
+                    .map(|i| if bits >> i & 1 == 0 { Team::COOKIE } else { Team::MILK })
+                    .collect::<Vec<_>>()
+            }))
+            .collect::<Vec<_>>();
+
+        for position in 0..columns.len().pow(N as u32) {
+            let mut board: Board<N> = Board::new();
+            let mut reference: VecBoard<N> = VecBoard::new();
+
+            let mut index = position;
+
+            for col in 1..=N {
+                for &tile in &columns[index % columns.len()] {
+                    board.place(col, tile).unwrap();
+                    reference.place(col, tile).unwrap();
+                }
+
+                index /= columns.len();
+            }
+
+            assert_eq!(board.winner(), reference.winner());
+            assert_eq!(board.full(), reference.full());
+            assert_eq!(board.to_string(), reference.to_string());
+        }
+    }
+}
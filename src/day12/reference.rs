@@ -0,0 +1,126 @@
+// The original board, one `Team` per tile. It is kept as the reference the
+// bitboard is checked and benchmarked against.
+
+use std::{fmt, sync::Arc};
+
+use crate::board::{Roster, Team};
+
+const WALL: char = '⬜';
+
+pub struct VecBoard<const N: usize> {
+    tiles: Vec<Vec<Team>>,
+    roster: Arc<Roster>,
+}
+
+impl<const N: usize> VecBoard<N> {
+    pub fn new() -> Self {
+        Self::with_roster(Arc::new(Roster::default()))
+    }
+
+    pub fn with_roster(roster: Arc<Roster>) -> Self {
+        VecBoard {
+            tiles: vec![vec![Team::default(); N]; N],
+            roster,
+        }
+    }
+
+    // N=4 for example:
+    //
+    // col 1             col 4
+    // vvvvv             vvvvv
+    // (0,0) (0,1) (0,2) (0,3)
+    // (1,0) (1,1) (1,2) (1,3)
+    // (2,0) (2,1) (2,2) (2,3)
+    // (3,0) (3,1) (3,2) (3,3)
+    pub fn place(&mut self, col: usize, tile: Team) -> Result<(), &'static str> {
+        if col < 1 || col > N {
+            return Err("meheh");
+        }
+
+        for i in (0..N).rev() {
+            if self.tiles[i][col - 1] == Team::EMPTY {
+                self.tiles[i][col - 1] = tile;
+                return Ok(())
+            }
+        }
+
+        Err("meh")
+    }
+
+    // Returns Some(winner) if a winner exists. This function never returns
+    // Some(Team::default()).
+    pub fn winner(&self) -> Option<Team> {
+        // Scan rows
+        for i in 0..N {
+            let maybe_winner = (0..N).fold(self.tiles[i][0], |acc, j| {
+                if acc == self.tiles[i][j] { acc } else { Team::default() }
+            });
+
+            if maybe_winner != Team::EMPTY {
+                return Some(maybe_winner);
+            }
+        }
+
+        // Scan cols
+        for i in 0..N {
+            let maybe_winner = (0..N).fold(self.tiles[0][i], |acc, j| {
+                if acc == self.tiles[j][i] { acc } else { Team::default() }
+            });
+
+            if maybe_winner != Team::EMPTY {
+                return Some(maybe_winner);
+            }
+        }
+
+        // Scan diagonal lines
+        let maybe_winner = (0..N).fold(self.tiles[0][0], |acc, i| {
+            if acc == self.tiles[i][i] { acc } else { Team::default() }
+        });
+
+        if maybe_winner != Team::EMPTY {
+            return Some(maybe_winner);
+        }
+
+        let maybe_winner = (0..N).fold(self.tiles[0][N - 1], |acc, i| {
+            if acc == self.tiles[i][N - 1 - i] { acc } else { Team::default() }
+        });
+
+        if maybe_winner != Team::EMPTY {
+            return Some(maybe_winner);
+        }
+
+        None
+    }
+
+    pub fn full(&self) -> bool {
+        self.tiles.iter().all(|col| col.iter().all(|&t| t != Team::default()))
+    }
+}
+
+impl<const N: usize> Default for VecBoard<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> fmt::Display for VecBoard<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> fmt::Result {
+        for row in 0..N {
+            write!(f, "{}", WALL)?;
+
+            for col in 0..N {
+                write!(f, "{}", self.roster.glyph(self.tiles[row][col]))?;
+            }
+
+            writeln!(f, "{}", WALL)?;
+        }
+
+        for _ in 0..(N + 2) {
+            write!(f, "{}", WALL)?;
+        }
+
+        writeln!(f)?;
+
+        Ok(())
+    }
+}
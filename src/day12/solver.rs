@@ -0,0 +1,237 @@
+// Perfect play for two teams taking turns, by negamax over every position
+// reachable from the one analyzed. Values are memoized in a transposition
+// table, so each position is only searched once.
+
+use std::{collections::HashMap, time::Instant};
+
+use serde::Serialize;
+
+use super::board::{Board, Team};
+
+// The value of winning on the next move. Every further move until the end of
+// the game takes one off, so that quicker wins are worth more and quicker
+// losses are worth less.
+const WIN: i32 = 1000;
+
+// How often the clock is checked, in nodes.
+const CLOCK_INTERVAL: u64 = 1024;
+
+// Enough for the empty 4x4 board to be solved completely.
+pub const MAX_NODES: u64 = 4_000_000;
+
+#[derive(Debug)]
+pub struct OutOfBudget;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Win,
+    Draw,
+    Loss,
+}
+
+// The result of a move for the team making it, and the number of moves until
+// the end of the game, counting this one.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Verdict {
+    pub outcome: Outcome,
+    pub plies: u32,
+}
+
+pub struct Solver {
+    table: HashMap<([u64; 4], Team), i32>,
+    nodes: u64,
+    max_nodes: u64,
+    deadline: Instant,
+}
+
+impl Solver {
+    pub fn new(max_nodes: u64, deadline: Instant) -> Self {
+        Solver {
+            table: HashMap::new(),
+            nodes: 0,
+            max_nodes,
+            deadline,
+        }
+    }
+
+    pub fn nodes(&self) -> u64 {
+        self.nodes
+    }
+
+    // Solves the move of `mover` in the given column, `other` moving next.
+    // Returns Ok(None) if the column is full.
+    pub fn solve<const N: usize>(
+        &mut self,
+        board: &Board<N>,
+        col: usize,
+        mover: Team,
+        other: Team,
+    ) -> Result<Option<Verdict>, OutOfBudget> {
+        let empty = (N * N) as u32 - board.position().iter().map(|t| t.count_ones()).sum::<u32>();
+
+        Ok(self.play(board, col, mover, other)?.map(|value| match value {
+            v if v > 0 => Verdict { outcome: Outcome::Win, plies: (WIN - v) as u32 + 1 },
+            v if v < 0 => Verdict { outcome: Outcome::Loss, plies: (WIN + v) as u32 + 1 },
+            // A draw is only reached by filling the board.
+            _ => Verdict { outcome: Outcome::Draw, plies: empty },
+        }))
+    }
+
+    // The value of the move for `mover`, or Ok(None) if the column is full.
+    fn play<const N: usize>(
+        &mut self,
+        board: &Board<N>,
+        col: usize,
+        mover: Team,
+        other: Team,
+    ) -> Result<Option<i32>, OutOfBudget> {
+        let mut next = board.clone();
+
+        if next.place(col, mover).is_err() {
+            return Ok(None);
+        }
+
+        if next.winner().is_some() {
+            return Ok(Some(WIN));
+        }
+
+        if next.full() {
+            return Ok(Some(0));
+        }
+
+        // A win for the other team in k moves is a loss for the mover in
+        // k + 1 moves, and the other way around.
+        let value = self.negamax(&next, other, mover)?;
+
+        Ok(Some(-value + value.signum()))
+    }
+
+    // The value of the position for `mover`, who is about to move. The game
+    // must not be over.
+    fn negamax<const N: usize>(
+        &mut self,
+        board: &Board<N>,
+        mover: Team,
+        other: Team,
+    ) -> Result<i32, OutOfBudget> {
+        let key = (board.position(), mover);
+
+        if let Some(&value) = self.table.get(&key) {
+            return Ok(value);
+        }
+
+        self.nodes += 1;
+
+        if self.nodes > self.max_nodes
+            || (self.nodes.is_multiple_of(CLOCK_INTERVAL) && Instant::now() > self.deadline) {
+            return Err(OutOfBudget);
+        }
+
+        let mut best = None;
+
+        for col in 1..=N {
+            if let Some(value) = self.play(board, col, mover, other)? {
+                best = best.max(Some(value));
+            }
+        }
+
+        let value = best.unwrap_or(0);
+
+        self.table.insert(key, value);
+
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn solver() -> Solver {
+        Solver::new(u64::MAX, Instant::now() + Duration::from_secs(3600))
+    }
+
+    #[test]
+    fn test_immediate_win() {
+        let mut board: Board<4> = Board::new();
+
+        for _ in 0..3 {
+            let _ = board.place(1, Team::COOKIE);
+            let _ = board.place(2, Team::MILK);
+        }
+
+        let mut solver = solver();
+
+        assert_eq!(
+            solver.solve(&board, 1, Team::COOKIE, Team::MILK).unwrap(),
+            Some(Verdict { outcome: Outcome::Win, plies: 1 }),
+        );
+
+        // Anything else lets milk win right away.
+        assert_eq!(
+            solver.solve(&board, 3, Team::COOKIE, Team::MILK).unwrap(),
+            Some(Verdict { outcome: Outcome::Loss, plies: 2 }),
+        );
+    }
+
+    #[test]
+    fn test_full_column() {
+        let mut board: Board<4> = Board::new();
+
+        for _ in 0..2 {
+            let _ = board.place(1, Team::COOKIE);
+            let _ = board.place(1, Team::MILK);
+        }
+
+        assert_eq!(solver().solve(&board, 1, Team::COOKIE, Team::MILK).unwrap(), None);
+    }
+
+    #[test]
+    fn test_out_of_budget() {
+        let board: Board<4> = Board::new();
+
+        let mut solver = Solver::new(100, Instant::now() + Duration::from_secs(3600));
+
+        assert!(solver.solve(&board, 1, Team::COOKIE, Team::MILK).is_err());
+    }
+
+    #[test]
+    fn test_solve_4_within_budget() {
+        let board: Board<4> = Board::new();
+
+        // Only the nodes: how long they take is for the benchmarks.
+        let mut solver = Solver::new(MAX_NODES, Instant::now() + Duration::from_secs(3600));
+
+        // As analyzed, one column after the other with the same table.
+        let verdicts = (1..=4)
+            .map(|col| solver.solve(&board, col, Team::COOKIE, Team::MILK).unwrap().unwrap())
+            .collect::<Vec<_>>();
+
+        assert!(solver.nodes() <= MAX_NODES);
+
+        // The board is symmetric.
+        assert_eq!(verdicts[0], verdicts[3]);
+        assert_eq!(verdicts[1], verdicts[2]);
+    }
+
+    #[test]
+    fn test_solve_3() {
+        let board: Board<3> = Board::new();
+
+        let mut solver = solver();
+
+        let verdicts = (1..=3)
+            .map(|col| solver.solve(&board, col, Team::COOKIE, Team::MILK).unwrap().unwrap())
+            .collect::<Vec<_>>();
+
+        // The board is symmetric.
+        assert_eq!(verdicts[0], verdicts[2]);
+
+        // Every line needs a tile from every column, so one column is
+        // enough to block the other team forever.
+        assert!(verdicts.iter().all(|v| v.outcome != Outcome::Loss));
+    }
+}
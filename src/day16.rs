@@ -1,18 +1,127 @@
-use axum::{extract::State, http::{self, HeaderMap, HeaderValue, StatusCode}, response::IntoResponse};
-use jwt_simple::{claims::Claims, prelude::{Duration, MACLike, RS256PublicKey, RS512PublicKey, RSAPublicKeyLike}, token::Token, JWTError};
-use serde_json::Value;
+use axum::{extract::State, http::{self, HeaderMap, HeaderValue, StatusCode}, response::IntoResponse, Form, Json};
+use axum_extra::extract::CookieJar;
+use cookie::Cookie;
+use jwt_simple::{claims::{Claims, JWTClaims}, prelude::Duration, token::Token};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sqlx::{query, query_scalar, types::Uuid, PgConnection, PgPool};
 
-use crate::AppState;
+use crate::{auth::{Client, GiftClaims, Principal}, config::{CookieConfig, SameSite}, AppState};
+
+pub(super) use jwe::Cipher;
+pub(super) use keyring::Keyring;
+pub(super) use registry::KeyRegistry;
+use refresh::{RefreshError, Session};
+pub(super) use validation::TokenError;
+
+mod jwe;
+mod keyring;
+mod refresh;
+mod registry;
+mod validation;
+
+pub(super) const GIFT_COOKIE: &str = "gift";
+const REFRESH_COOKIE: &str = "gift_refresh";
+const REFRESH_PATH: &str = "/16/refresh";
+
+// The cookie holding a gift token, with the configured attributes.
+fn gift_cookie(config: &CookieConfig, lifetime: u64, jwt: String) -> Cookie<'static> {
+    let same_site = match config.same_site {
+        SameSite::Strict => cookie::SameSite::Strict,
+        SameSite::Lax => cookie::SameSite::Lax,
+        SameSite::None => cookie::SameSite::None,
+    };
+
+    Cookie::build((GIFT_COOKIE, jwt))
+        .http_only(config.http_only)
+        .secure(config.secure)
+        .same_site(same_site)
+        .path(config.path.clone())
+        .max_age(cookie::time::Duration::seconds(config.max_age.unwrap_or(lifetime) as i64))
+        .build()
+}
+
+// The cookie holding a refresh token, only ever sent to /16/refresh.
+fn refresh_cookie(config: &CookieConfig, lifetime: u64, token: String) -> Cookie<'static> {
+    let mut cookie = gift_cookie(config, lifetime, token);
+
+    cookie.set_name(REFRESH_COOKIE);
+    cookie.set_path(REFRESH_PATH);
+    cookie.set_max_age(cookie::time::Duration::seconds(lifetime as i64));
+
+    cookie
+}
+
+// The claims of a gift token signed with one of our keys and valid under the
+// gift policy, revoked or not. Encrypted tokens are decrypted first.
+pub(super) fn verify_gift(state: &AppState, token: &str) -> Result<JWTClaims<Value>, TokenError> {
+    let encryption = &state.config.gift.encryption;
+
+    let jwt = match (jwe::is_encrypted(token), &state.cipher) {
+        (true, Some(cipher)) => cipher.decrypt(token)?,
+        (true, None) => return Err(TokenError::Undecryptable),
+        (false, Some(_)) if !encryption.accept_unencrypted => return Err(TokenError::Unencrypted),
+        (false, _) => token.to_string(),
+    };
+    let jwt = jwt.as_str();
+
+    let metadata = Token::decode_metadata(jwt).map_err(|_| TokenError::Malformed)?;
+    let keys = state.keyring.verifying_keys(keyring::now(), metadata.key_id());
+
+    state.config.gift.validation.verify(keys, |key, options| key.verify(jwt, options))
+}
+
+pub(super) async fn is_revoked(pool: &PgPool, claims: &JWTClaims<Value>) -> Result<bool, sqlx::Error> {
+    match &claims.jwt_id {
+        Some(jti) => query_scalar("SELECT EXISTS (SELECT 1 FROM revoked_tokens WHERE jti = $1)")
+            .bind(jti)
+            .fetch_one(pool)
+            .await,
+        None => Ok(false),
+    }
+}
+
+// Add the token with the jti to the denylist until it expires at the given
+// second, forgetting about those that expired already.
+async fn deny(conn: &mut PgConnection, jti: &str, expires_at: Option<u64>) -> Result<(), sqlx::Error> {
+    query("DELETE FROM revoked_tokens WHERE expires_at < CURRENT_TIMESTAMP")
+        .execute(&mut *conn)
+        .await?;
+
+    query(r#"
+        INSERT INTO revoked_tokens (jti, expires_at)
+        VALUES ($1, to_timestamp($2))
+        ON CONFLICT (jti) DO NOTHING
+    "#)
+        .bind(jti)
+        .bind(expires_at.map(|exp| exp as f64))
+        .execute(conn)
+        .await?;
+
+    Ok(())
+}
+
+// Deny the token with the jti until it expires, along with the refresh
+// tokens issued with it and the gift tokens issued with those.
+async fn revoke_token(pool: &PgPool, jti: &str, expires_at: Option<u64>) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+
+    refresh::revoke(&mut tx, jti).await?;
+    deny(&mut tx, jti, expires_at).await?;
+
+    tx.commit().await
+}
 
 pub(super) async fn wrap(
     headers: HeaderMap,
     State(state): State<AppState>,
+    client: Option<Client>,
     payload: String,
 ) -> impl IntoResponse {
     let mut response_headers: HeaderMap = HeaderMap::new();
 
-    if !headers.get(http::header::CONTENT_TYPE)
-        .is_some_and(|v| v == "application/json") {
+    if headers.get(http::header::CONTENT_TYPE)
+        .is_none_or(|v| v != "application/json") {
         return (
             StatusCode::BAD_REQUEST,
             response_headers,
@@ -20,85 +129,275 @@ pub(super) async fn wrap(
         )
     }
 
-    let claims = Claims::with_custom_claims::<Value>(
-        serde_json::from_str(payload.as_str()).unwrap(),
-        Duration::from_hours(1)
-    );
-    let jwt = state.jwt_key.authenticate(claims).unwrap();
+    let payload: Value = match serde_json::from_str(payload.as_str()) {
+        Ok(payload) => payload,
+        Err(_) => return (StatusCode::BAD_REQUEST, response_headers, String::new()),
+    };
 
-    response_headers.insert(
-        http::header::SET_COOKIE,
-        HeaderValue::from_str(format!("gift={}", jwt).as_str()).unwrap(),
-    );
+    // Roles are granted by the operators through their clients, not wrapped
+    // by anyone.
+    if payload.get(&state.config.auth.roles_claim).is_some() && client.is_none() {
+        return (
+            StatusCode::BAD_REQUEST,
+            response_headers,
+            String::new(),
+        )
+    }
+
+    let session = Session { family: Uuid::new_v4(), payload };
+
+    let mut conn = match state.pool.acquire().await {
+        Ok(conn) => conn,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, response_headers, String::new()),
+    };
+
+    let cookies = match issue_gift(&state, &mut conn, &session).await {
+        Ok(cookies) => cookies,
+        Err(status) => return (status, response_headers, String::new()),
+    };
+
+    for cookie in cookies {
+        match HeaderValue::from_str(cookie.to_string().as_str()) {
+            Ok(value) => response_headers.append(http::header::SET_COOKIE, value),
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, HeaderMap::new(), String::new()),
+        };
+    }
 
     (StatusCode::OK, response_headers, String::new())
 }
 
+// Sign a gift token for the session, and issue the refresh token that comes
+// with it. Respond with the cookies holding them.
+async fn issue_gift(
+    state: &AppState,
+    conn: &mut PgConnection,
+    session: &Session,
+) -> Result<[Cookie<'static>; 2], StatusCode> {
+    let config = &state.config.gift;
+
+    let key = state.keyring.signing_key(config.algorithm, keyring::now())
+        .ok_or(StatusCode::SERVICE_UNAVAILABLE)?;
+
+    let mut claims = Claims::with_custom_claims(
+        session.payload.clone(),
+        Duration::from_secs(config.lifetime),
+    ).with_jwt_id(Uuid::new_v4().to_string());
+
+    if let Some(issuer) = &config.issuer {
+        claims = claims.with_issuer(issuer);
+    }
+
+    if let Some(audience) = &config.audience {
+        claims = claims.with_audience(audience);
+    }
+
+    let jwt = key.sign(claims.clone()).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let jwt = match &state.cipher {
+        Some(cipher) => cipher.encrypt(&jwt),
+        None => jwt,
+    };
+
+    let refresh_token = refresh::issue(conn, session, config.refresh_lifetime, &claims)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok([
+        gift_cookie(&config.cookie, config.lifetime, jwt),
+        refresh_cookie(&config.cookie, config.refresh_lifetime, refresh_token),
+    ])
+}
+
+// GET /16/unwrap: Respond with the gift in the gift cookie. If there is none,
+// or it is not valid, the body says why.
 pub(super) async fn unwrap(
-    headers: HeaderMap,
+    jar: CookieJar,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let claims = match jar.get(GIFT_COOKIE) {
+        Some(cookie) => verify_gift(&state, cookie.value()),
+        None => Err(TokenError::Missing),
+    };
+
+    let claims = match claims {
+        Ok(claims) => claims,
+        Err(e) => return (StatusCode::BAD_REQUEST, String::from(e.code())),
+    };
+
+    match is_revoked(&state.pool, &claims).await {
+        Ok(false) => (
+            StatusCode::OK,
+            serde_json::to_string(&claims.custom).unwrap(),
+        ),
+        Ok(true) => (StatusCode::BAD_REQUEST, String::from(TokenError::Revoked.code())),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, String::new()),
+    }
+}
+
+// POST /16/revoke: Revoke the gift token in the body, or the one in the gift
+// cookie if the body is empty, so that it is not accepted anymore even though
+// it has not expired. The refresh tokens issued with it are revoked too.
+// Tokens without a jti cannot be revoked.
+pub(super) async fn revoke(
+    jar: CookieJar,
+    State(state): State<AppState>,
+    jwt: String,
+) -> (StatusCode, String) {
+    let jwt = match jwt.trim() {
+        "" => match jar.get(GIFT_COOKIE) {
+            Some(cookie) => cookie.value().to_string(),
+            None => return (StatusCode::BAD_REQUEST, String::from(TokenError::Missing.code())),
+        },
+        jwt => jwt.to_string(),
+    };
+
+    let claims = match verify_gift(&state, &jwt) {
+        Ok(claims) => claims,
+        Err(e) => return (StatusCode::BAD_REQUEST, String::from(e.code())),
+    };
+
+    let Some(jti) = &claims.jwt_id else {
+        return (StatusCode::BAD_REQUEST, String::from("missing_jti"));
+    };
+
+    match revoke_token(&state.pool, jti, claims.expires_at.map(|exp| exp.as_secs())).await {
+        Ok(()) => (StatusCode::OK, String::new()),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, String::new()),
+    }
+}
+
+// POST /16/logout: Revoke the token of the caller, if any, along with the
+// refresh tokens issued with it, and clear both cookies.
+pub(super) async fn logout(
+    principal: Option<Principal>,
+    jar: CookieJar,
     State(state): State<AppState>,
 ) -> impl IntoResponse {
-    if let Some(cookie) = headers.get(http::header::COOKIE) {
-        let parts = cookie.to_str().unwrap().split("=").collect::<Vec<_>>();
-        
-        if parts[0] == "gift" {
-            let jwt = parts[1];
-
-            if let Ok(res) = state.jwt_key.verify_token::<Value>(jwt, None) {
-                return (
-                    StatusCode::OK,
-                    serde_json::to_string(&res.custom).unwrap(),
-                )
-            }
+    if let Some(GiftClaims { jwt_id: Some(jti), expires_at, .. }) = principal.map(|principal| principal.claims) {
+        if revoke_token(&state.pool, &jti, expires_at).await.is_err() {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, String::new()));
         }
     }
 
-    (StatusCode::BAD_REQUEST, String::new())
+    let mut cookie = gift_cookie(&state.config.gift.cookie, 0, String::new());
+    let mut refresh_cookie = refresh_cookie(&state.config.gift.cookie, 0, String::new());
+
+    cookie.make_removal();
+    refresh_cookie.make_removal();
+
+    Ok((StatusCode::OK, jar.add(cookie).add(refresh_cookie)))
+}
+
+// GET /16/me: The claims of the gift token of the caller, as the routes
+// behind the auth config see them.
+pub(super) async fn me(principal: Principal) -> impl IntoResponse {
+    Json(principal.claims)
 }
 
+// POST /16/refresh: Trade the refresh token in the refresh cookie for a new
+// gift token and the next refresh token. Using a refresh token twice revokes
+// every token issued since the gift was wrapped.
+pub(super) async fn refresh(
+    jar: CookieJar,
+    State(state): State<AppState>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    let token = match jar.get(REFRESH_COOKIE) {
+        Some(cookie) => cookie.value().to_string(),
+        None => return Err((StatusCode::BAD_REQUEST, String::from(TokenError::Missing.code()))),
+    };
+
+    let internal = |_| (StatusCode::INTERNAL_SERVER_ERROR, String::new());
+    let mut tx = state.pool.begin().await.map_err(internal)?;
+
+    // Rolled back if the next tokens are not issued, so that the refresh token
+    // can be used again.
+    let cookies = match refresh::redeem(&mut tx, &token).await {
+        Ok(session) => issue_gift(&state, &mut tx, &session).await.map_err(|status| (status, String::new()))?,
+        Err(RefreshError::Database) => return Err((StatusCode::INTERNAL_SERVER_ERROR, String::new())),
+        Err(e) => {
+            // The family is revoked if the token was reused.
+            tx.commit().await.map_err(internal)?;
+
+            return Err((StatusCode::UNAUTHORIZED, String::from(e.code())));
+        },
+    };
+
+    tx.commit().await.map_err(internal)?;
+
+    let [cookie, refresh_cookie] = cookies;
+
+    Ok((StatusCode::OK, jar.add(cookie).add(refresh_cookie)))
+}
+
+#[derive(Deserialize)]
+pub(super) struct Introspection {
+    token: String,
+}
+
+// POST /16/introspect: Tell an authenticated client whether the token in the
+// form is one /16/unwrap or /16/decode would accept, and if so with which
+// claims, as in RFC 7662.
+pub(super) async fn introspect(
+    _: Client,
+    State(state): State<AppState>,
+    Form(form): Form<Introspection>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    let claims = verify_gift(&state, &form.token)
+        .or_else(|_| state.key_registry.verify(&form.token, &state.config.decode.validation));
+
+    let claims = match claims {
+        Ok(claims) => claims,
+        Err(_) => return Ok(Json(json!({"active": false}))),
+    };
+
+    match is_revoked(&state.pool, &claims).await {
+        Ok(false) => {},
+        Ok(true) => return Ok(Json(json!({"active": false}))),
+        Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+
+    // The registered claims, along with the custom ones.
+    let mut response = serde_json::to_value(&claims).unwrap();
+
+    response["active"] = Value::Bool(true);
+
+    Ok(Json(response))
+}
+
+// GET /.well-known/jwks.json: Respond with the public keys gift tokens are or
+// will soon be signed with, none as long as they are signed with HS256.
+pub(super) async fn jwks(
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    let (jwks, max_age) = state.keyring.jwks(keyring::now(), state.config.gift.jwks_max_age);
+    let mut headers = HeaderMap::new();
+
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/jwk-set+json"),
+    );
+    headers.insert(
+        http::header::CACHE_CONTROL,
+        HeaderValue::from_str(format!("public, max-age={}", max_age).as_str()).unwrap(),
+    );
+
+    (StatusCode::OK, headers, jwks.to_string())
+}
+
+// POST /16/decode: Verify a JWT signed by Santa or any of the configured keys,
+// respond with its claims, or else why it is not valid.
 pub(super) async fn decode(
     State(state): State<AppState>,
     jwt: String,
 ) -> Result<impl IntoResponse, impl IntoResponse> {
-    if let Ok(metadata) = Token::decode_metadata(jwt.as_str()) {
-        let claims = match metadata.algorithm() {
-            // FIXME: I know it is perhaps too dumb to initialize
-            // RS(256|512)PublicKey for each request, but the API
-            // is not flexible enough, unfortunately. For example,
-            // if there were ways to create RS(256|512)PublicKey
-            // from RSAPublicKey, then we could keep RSAPublicKey
-            // to AppStatus and reuse it. But jwt_simple API currently
-            // does not allow that.
-            "RS256" => {
-                RS256PublicKey::from_pem(state.santa_public_pem).unwrap()
-                    .verify_token::<Value>(jwt.as_str(), None)
-            },
-            "RS512" => {
-                RS512PublicKey::from_pem(state.santa_public_pem).unwrap()
-                    .verify_token::<Value>(jwt.as_str(), None)
-            },
-            _ => return Err((StatusCode::BAD_REQUEST, String::new())),
-        };
-
-        match claims {
-            Ok(claims) => Ok((
-                StatusCode::OK,
-                serde_json::to_string(&claims.custom).unwrap(),
-            )),
-            Err(e) => {
-                match e.downcast::<JWTError>() {
-                    Ok(JWTError::InvalidSignature) => Err((
-                        StatusCode::UNAUTHORIZED,
-                        String::new()
-                    )),
-                    _ => Err((StatusCode::BAD_REQUEST, String::new()))
-                }
-            }
-        }
-    } else {
-        Err((
-            StatusCode::BAD_REQUEST,
-            String::new(),
-        ))
+    match state.key_registry.verify(jwt.as_str(), &state.config.decode.validation) {
+        Ok(claims) => Ok((
+            StatusCode::OK,
+            serde_json::to_string(&claims.custom).unwrap(),
+        )),
+        Err(e @ (TokenError::UnknownKey | TokenError::InvalidSignature)) => Err((
+            StatusCode::UNAUTHORIZED,
+            String::from(e.code()),
+        )),
+        Err(e) => Err((StatusCode::BAD_REQUEST, String::from(e.code()))),
     }
 }
@@ -0,0 +1,141 @@
+use aes_gcm::{aead::{Aead, KeyInit, Payload}, Aes256Gcm, Nonce};
+use jwt_simple::reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder, Encoder};
+use serde_json::{json, Value};
+
+use super::TokenError;
+
+// Gift tokens encrypted as compact JWE, with a key shared by the instances
+// (alg "dir") and AES-256-GCM: the signed token is the plaintext, so that the
+// holder of the cookie cannot read the gift inside.
+//
+//   BASE64URL(header) . "" . BASE64URL(iv) . BASE64URL(ciphertext) . BASE64URL(tag)
+
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Clone)]
+pub(crate) struct Cipher(Aes256Gcm);
+
+// Whether the token is a JWE rather than a JWS, which has three parts.
+pub(super) fn is_encrypted(token: &str) -> bool {
+    token.split('.').count() == 5
+}
+
+fn decode(part: &str) -> Result<Vec<u8>, TokenError> {
+    Base64UrlSafeNoPadding::decode_to_vec(part, None).map_err(|_| TokenError::Malformed)
+}
+
+impl Cipher {
+    // The key is 32 bytes, base64url encoded.
+    pub(crate) fn from_key(key: &str) -> Result<Self, String> {
+        Base64UrlSafeNoPadding::decode_to_vec(key, None)
+            .ok()
+            .and_then(|key| Aes256Gcm::new_from_slice(&key).ok())
+            .map(Cipher)
+            .ok_or_else(|| String::from("encryption key is not 32 bytes of base64url"))
+    }
+
+    pub(super) fn encrypt(&self, jwt: &str) -> String {
+        let header = json!({"alg": "dir", "enc": "A256GCM", "cty": "JWT"}).to_string();
+        let header = Base64UrlSafeNoPadding::encode_to_string(header).unwrap();
+        let iv = rand::random::<[u8; IV_LEN]>();
+
+        // The protected header is authenticated along with the ciphertext.
+        let mut ciphertext = self.0.encrypt(
+            &Nonce::from(iv),
+            Payload { msg: jwt.as_bytes(), aad: header.as_bytes() },
+        ).unwrap();
+        let tag = ciphertext.split_off(ciphertext.len() - TAG_LEN);
+
+        [
+            header,
+            String::new(),
+            Base64UrlSafeNoPadding::encode_to_string(iv).unwrap(),
+            Base64UrlSafeNoPadding::encode_to_string(ciphertext).unwrap(),
+            Base64UrlSafeNoPadding::encode_to_string(tag).unwrap(),
+        ].join(".")
+    }
+
+    // The signed token inside, yet to be verified.
+    pub(super) fn decrypt(&self, token: &str) -> Result<String, TokenError> {
+        let parts: Vec<&str> = token.split('.').collect();
+
+        let [header, encrypted_key, iv, ciphertext, tag] = parts[..] else {
+            return Err(TokenError::Malformed);
+        };
+
+        let fields: Value = serde_json::from_slice(&decode(header)?)
+            .map_err(|_| TokenError::Malformed)?;
+
+        if fields["alg"] != "dir" || fields["enc"] != "A256GCM" {
+            return Err(TokenError::UnsupportedAlgorithm);
+        }
+
+        let iv: [u8; IV_LEN] = decode(iv)?.try_into().map_err(|_| TokenError::Malformed)?;
+        let mut ciphertext = decode(ciphertext)?;
+        let tag = decode(tag)?;
+
+        if !encrypted_key.is_empty() || tag.len() != TAG_LEN {
+            return Err(TokenError::Malformed);
+        }
+
+        ciphertext.extend(tag);
+
+        let jwt = self.0.decrypt(
+            &Nonce::from(iv),
+            Payload { msg: &ciphertext, aad: header.as_bytes() },
+        ).map_err(|_| TokenError::Undecryptable)?;
+
+        String::from_utf8(jwt).map_err(|_| TokenError::Malformed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key() -> String {
+        Base64UrlSafeNoPadding::encode_to_string(rand::random::<[u8; 32]>()).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let cipher = Cipher::from_key(&key()).unwrap();
+        let token = cipher.encrypt("a.b.c");
+
+        assert!(is_encrypted(&token));
+        assert!(!is_encrypted("a.b.c"));
+        assert!(!token.contains("a.b.c"));
+        assert_eq!(cipher.decrypt(&token), Ok(String::from("a.b.c")));
+        assert_ne!(cipher.encrypt("a.b.c"), token);
+    }
+
+    #[test]
+    fn test_tampering() {
+        let cipher = Cipher::from_key(&key()).unwrap();
+        let token = cipher.encrypt("a.b.c");
+        let parts: Vec<&str> = token.split('.').collect();
+
+        let other = Cipher::from_key(&key()).unwrap();
+
+        assert_eq!(other.decrypt(&token), Err(TokenError::Undecryptable));
+
+        let header = Base64UrlSafeNoPadding::encode_to_string(r#"{"alg":"dir","enc":"A256GCM"}"#).unwrap();
+        let swapped = [header.as_str(), "", parts[2], parts[3], parts[4]].join(".");
+
+        assert_eq!(cipher.decrypt(&swapped), Err(TokenError::Undecryptable));
+
+        let header = Base64UrlSafeNoPadding::encode_to_string(r#"{"alg":"RSA-OAEP","enc":"A256GCM"}"#).unwrap();
+        let rsa = [header.as_str(), "", parts[2], parts[3], parts[4]].join(".");
+
+        assert_eq!(cipher.decrypt(&rsa), Err(TokenError::UnsupportedAlgorithm));
+        assert_eq!(cipher.decrypt("a.b.c"), Err(TokenError::Malformed));
+    }
+
+    #[test]
+    fn test_key() {
+        assert!(Cipher::from_key(&key()).is_ok());
+        assert!(Cipher::from_key("c2hvcnQ").is_err());
+        assert!(Cipher::from_key("not base64!").is_err());
+    }
+}
@@ -0,0 +1,411 @@
+use std::{collections::HashSet, fs, path::Path, time::{SystemTime, UNIX_EPOCH}};
+
+use jwt_simple::{
+    claims::JWTClaims,
+    prelude::{
+        EdDSAKeyPairLike, EdDSAPublicKeyLike, Ed25519KeyPair, HS256Key, MACLike, RS256KeyPair,
+        RSAKeyPairLike, RSAPublicKeyLike, VerificationOptions,
+    },
+    reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder, Encoder},
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::config::Algorithm;
+
+const MIN_SECRET_LEN: usize = 32;
+
+// A key as written in the keyring file. Times are seconds since the epoch.
+//
+//   [[keys]]
+//   kid = "2024-12"
+//   alg = "EdDSA"            # optional, HS256 by default
+//   secret = "<unpadded base64url>"
+//   not_before = 1733011200  # optional, signs from then on
+//   retire_at = 1735693200   # optional, not even verified from then on
+//
+// The secret is the key itself for HS256 (at least 32 bytes), the PKCS#1 or
+// PKCS#8 DER private key for RS256, and the 64 bytes of the key pair for
+// EdDSA. `cargo run --example keygen -- <alg>` prints a new one.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyEntry {
+    kid: String,
+    #[serde(default = "default_alg")]
+    alg: Algorithm,
+    secret: String,
+    #[serde(default)]
+    not_before: Option<u64>,
+    #[serde(default)]
+    retire_at: Option<u64>,
+}
+
+fn default_alg() -> Algorithm {
+    Algorithm::HS256
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeyringFile {
+    keys: Vec<KeyEntry>,
+}
+
+enum KeyPair {
+    HS256(HS256Key),
+    RS256(Box<RS256KeyPair>),
+    EdDSA(Ed25519KeyPair),
+}
+
+pub(super) struct Key {
+    kid: String,
+    pair: KeyPair,
+    not_before: u64,
+    retire_at: Option<u64>,
+}
+
+impl Key {
+    fn algorithm(&self) -> Algorithm {
+        match self.pair {
+            KeyPair::HS256(_) => Algorithm::HS256,
+            KeyPair::RS256(_) => Algorithm::RS256,
+            KeyPair::EdDSA(_) => Algorithm::EdDSA,
+        }
+    }
+
+    fn retired(&self, now: u64) -> bool {
+        self.retire_at.is_some_and(|t| t <= now)
+    }
+
+    pub(super) fn sign(&self, claims: JWTClaims<Value>) -> Result<String, jwt_simple::Error> {
+        match &self.pair {
+            KeyPair::HS256(key) => key.authenticate(claims),
+            KeyPair::RS256(key) => key.sign(claims),
+            KeyPair::EdDSA(key) => key.sign(claims),
+        }
+    }
+
+    pub(super) fn verify(&self, jwt: &str, options: &VerificationOptions) -> Result<JWTClaims<Value>, jwt_simple::Error> {
+        let options = Some(options.clone());
+
+        match &self.pair {
+            KeyPair::HS256(key) => key.verify_token(jwt, options),
+            KeyPair::RS256(key) => key.public_key().verify_token(jwt, options),
+            KeyPair::EdDSA(key) => key.public_key().verify_token(jwt, options),
+        }
+    }
+
+    // The public half as a JWK, none for HS256 keys, which are secret.
+    fn jwk(&self) -> Option<Value> {
+        match &self.pair {
+            KeyPair::HS256(_) => None,
+            KeyPair::RS256(key) => {
+                let components = key.public_key().to_components();
+
+                Some(json!({
+                    "kty": "RSA",
+                    "kid": self.kid,
+                    "alg": "RS256",
+                    "use": "sig",
+                    "n": Base64UrlSafeNoPadding::encode_to_string(components.n).unwrap(),
+                    "e": Base64UrlSafeNoPadding::encode_to_string(components.e).unwrap(),
+                }))
+            },
+            KeyPair::EdDSA(key) => Some(json!({
+                "kty": "OKP",
+                "crv": "Ed25519",
+                "kid": self.kid,
+                "alg": "EdDSA",
+                "use": "sig",
+                "x": Base64UrlSafeNoPadding::encode_to_string(key.public_key().to_bytes()).unwrap(),
+            })),
+        }
+    }
+}
+
+// The keys used for gift tokens. The newest active key for the configured
+// algorithm signs, and every key that is not retired yet verifies, so a key
+// can be rotated by adding its successor with a `not_before` and giving it a
+// `retire_at` at least one token lifetime later. Since everything is
+// scheduled in the file, replicas sharing the file switch keys at the same
+// time without talking to each other.
+pub(crate) struct Keyring {
+    keys: Vec<Key>,
+}
+
+impl Keyring {
+    // Read the keyring at `path`, which must have a key for `algorithm`. It
+    // is provisioned by the operators rather than generated here, so that
+    // every instance and deploy signs with the same keys.
+    pub(crate) fn load(path: impl AsRef<Path>, algorithm: Algorithm) -> Result<Self, String> {
+        let path = path.as_ref();
+        let in_file = |e: String| format!("{}: {}", path.display(), e);
+        let s = fs::read_to_string(path).map_err(|e| in_file(e.to_string()))?;
+        let keyring = Keyring::parse(&s).map_err(in_file)?;
+
+        if !keyring.has(algorithm, now()) {
+            return Err(in_file(format!("no {:?} key that is not retired", algorithm)));
+        }
+
+        Ok(keyring)
+    }
+
+    fn parse(s: &str) -> Result<Self, String> {
+        let file: KeyringFile = toml::from_str(s).map_err(|e| e.to_string())?;
+        let mut kids = HashSet::new();
+        let mut keys = Vec::new();
+
+        for entry in file.keys {
+            if !kids.insert(entry.kid.clone()) {
+                return Err(format!("duplicate kid {}", entry.kid));
+            }
+
+            let secret = Base64UrlSafeNoPadding::decode_to_vec(&entry.secret, None)
+                .map_err(|_| format!("secret of {} is not base64url", entry.kid))?;
+            let invalid = |e: jwt_simple::Error| format!("secret of {} is invalid: {}", entry.kid, e);
+            let pair = match entry.alg {
+                Algorithm::HS256 if secret.len() < MIN_SECRET_LEN => {
+                    return Err(format!("secret of {} is shorter than {} bytes", entry.kid, MIN_SECRET_LEN));
+                },
+                Algorithm::HS256 => KeyPair::HS256(HS256Key::from_bytes(&secret).with_key_id(&entry.kid)),
+                Algorithm::RS256 => KeyPair::RS256(Box::new(RS256KeyPair::from_der(&secret).map_err(invalid)?.with_key_id(&entry.kid))),
+                Algorithm::EdDSA => KeyPair::EdDSA(Ed25519KeyPair::from_bytes(&secret).map_err(invalid)?.with_key_id(&entry.kid)),
+            };
+
+            keys.push(Key {
+                kid: entry.kid,
+                pair,
+                not_before: entry.not_before.unwrap_or(0),
+                retire_at: entry.retire_at,
+            });
+        }
+
+        if keys.is_empty() {
+            return Err(String::from("no keys"));
+        }
+
+        Ok(Keyring { keys })
+    }
+
+    // Whether a key for `algorithm` is not retired at `now`.
+    fn has(&self, algorithm: Algorithm, now: u64) -> bool {
+        self.keys.iter().any(|k| k.algorithm() == algorithm && !k.retired(now))
+    }
+
+    // The key to sign new tokens with, if any key for `algorithm` is active
+    // at `now`.
+    pub(super) fn signing_key(&self, algorithm: Algorithm, now: u64) -> Option<&Key> {
+        self.keys.iter()
+            .filter(|k| k.algorithm() == algorithm && k.not_before <= now && !k.retired(now))
+            .max_by_key(|k| k.not_before)
+    }
+
+    // The keys a token may have been signed with: the one named by its kid,
+    // or any of them for tokens without one.
+    pub(super) fn verifying_keys<'a>(
+        &'a self,
+        now: u64,
+        kid: Option<&'a str>,
+    ) -> impl Iterator<Item = &'a Key> {
+        self.keys.iter()
+            .filter(move |k| !k.retired(now))
+            .filter(move |k| kid.is_none_or(|kid| k.kid == kid))
+    }
+
+    // The public keys that are not retired at `now` as a JWK set, including
+    // those that will only sign later, so verifiers already have them by
+    // then. Also how long the set stays unchanged, at most `max_age`, if the
+    // keyring file is not edited meanwhile.
+    pub(super) fn jwks(&self, now: u64, max_age: u64) -> (Value, u64) {
+        let keys = self.keys.iter()
+            .filter(|k| !k.retired(now))
+            .filter_map(Key::jwk)
+            .collect::<Vec<_>>();
+        let max_age = self.keys.iter()
+            .filter(|k| k.algorithm() != Algorithm::HS256)
+            .filter_map(|k| k.retire_at)
+            .filter(|&t| t > now)
+            .map(|t| t - now)
+            .fold(max_age, u64::min);
+
+        (json!({"keys": keys}), max_age)
+    }
+}
+
+pub(super) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use jwt_simple::prelude::{Claims, Duration};
+
+    use super::*;
+
+    const SECRET_A: &str = "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA";
+    const SECRET_B: &str = "BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBA";
+
+    // A keyring file with one new key, active from `now`, as the keygen
+    // example prints it.
+    fn generate(algorithm: Algorithm, now: u64) -> String {
+        let secret = match algorithm {
+            Algorithm::HS256 => HS256Key::generate().to_bytes(),
+            Algorithm::RS256 => RS256KeyPair::generate(2048).unwrap().to_der().unwrap(),
+            Algorithm::EdDSA => Ed25519KeyPair::generate().to_bytes(),
+        };
+
+        format!(
+            "[[keys]]\nkid = \"{:016x}\"\nalg = \"{:?}\"\nsecret = \"{}\"\nnot_before = {}\n",
+            rand::random::<u64>(),
+            algorithm,
+            Base64UrlSafeNoPadding::encode_to_string(secret).unwrap(),
+            now,
+        )
+    }
+
+    fn rotating() -> Keyring {
+        Keyring::parse(&format!(r#"
+            [[keys]]
+            kid = "a"
+            secret = "{SECRET_A}"
+            retire_at = 200
+
+            [[keys]]
+            kid = "b"
+            secret = "{SECRET_B}"
+            not_before = 100
+        "#)).unwrap()
+    }
+
+    fn claims() -> JWTClaims<Value> {
+        Claims::with_custom_claims(json!({"gift": "cookie"}), Duration::from_hours(1))
+    }
+
+    fn kid(key: Option<&Key>) -> Option<&str> {
+        key.map(|k| k.kid.as_str())
+    }
+
+    #[test]
+    fn test_signing_key() {
+        let keyring = rotating();
+
+        assert_eq!(kid(keyring.signing_key(Algorithm::HS256, 0)), Some("a"));
+        assert_eq!(kid(keyring.signing_key(Algorithm::HS256, 99)), Some("a"));
+        assert_eq!(kid(keyring.signing_key(Algorithm::HS256, 100)), Some("b"));
+        assert_eq!(kid(keyring.signing_key(Algorithm::HS256, 1000)), Some("b"));
+        assert_eq!(kid(keyring.signing_key(Algorithm::EdDSA, 1000)), None);
+    }
+
+    #[test]
+    fn test_grace_period() {
+        let keyring = rotating();
+        let token = keyring.signing_key(Algorithm::HS256, 50).unwrap()
+            .sign(claims())
+            .unwrap();
+
+        for (now, valid) in [(50, true), (150, true), (199, true), (200, false)] {
+            let verified = keyring.verifying_keys(now, Some("a"))
+                .any(|k| k.verify(&token, &VerificationOptions::default()).is_ok());
+
+            assert_eq!(verified, valid);
+        }
+
+        assert_eq!(keyring.verifying_keys(150, None).count(), 2);
+        assert_eq!(keyring.verifying_keys(250, None).count(), 1);
+        assert_eq!(keyring.verifying_keys(150, Some("c")).count(), 0);
+    }
+
+    #[test]
+    fn test_generate() {
+        let keyring = Keyring::parse(&generate(Algorithm::HS256, 1000)).unwrap();
+
+        assert!(keyring.signing_key(Algorithm::HS256, 999).is_none());
+        assert!(keyring.signing_key(Algorithm::HS256, 1000).is_some());
+        assert_eq!(kid(keyring.signing_key(Algorithm::HS256, 1000)).unwrap().len(), 16);
+        assert_eq!(keyring.jwks(1000, 3600), (json!({"keys": []}), 3600));
+    }
+
+    #[test]
+    fn test_eddsa() {
+        let mut s = generate(Algorithm::EdDSA, 100);
+
+        s.push_str(&format!("\n[[keys]]\nkid = \"a\"\nsecret = \"{SECRET_A}\"\n"));
+
+        let keyring = Keyring::parse(&s).unwrap();
+        let key = keyring.signing_key(Algorithm::EdDSA, 100).unwrap();
+        let token = key.sign(claims()).unwrap();
+        let verify = |kid| keyring.verifying_keys(100, Some(kid)).next().unwrap().verify(&token, &VerificationOptions::default());
+
+        assert_eq!(verify(&key.kid).unwrap().custom, json!({"gift": "cookie"}));
+        assert!(verify("a").is_err());
+
+        let (jwks, max_age) = keyring.jwks(100, 3600);
+
+        assert_eq!(max_age, 3600);
+        assert_eq!(jwks["keys"].as_array().unwrap().len(), 1);
+        assert_eq!(jwks["keys"][0]["kid"], key.kid);
+        assert_eq!(jwks["keys"][0]["crv"], "Ed25519");
+    }
+
+    #[test]
+    fn test_jwks_max_age() {
+        let s = [
+            generate(Algorithm::EdDSA, 100).replace("not_before = 100", "retire_at = 1000"),
+            generate(Algorithm::EdDSA, 500),
+        ].join("\n");
+        let keyring = Keyring::parse(&s).unwrap();
+
+        // The next key is published before it signs.
+        assert_eq!(keyring.jwks(100, 3600).0["keys"].as_array().unwrap().len(), 2);
+        assert_eq!(keyring.jwks(100, 3600).1, 900);
+        assert_eq!(keyring.jwks(900, 60).1, 60);
+        assert_eq!(keyring.jwks(1000, 3600).0["keys"].as_array().unwrap().len(), 1);
+        assert_eq!(keyring.jwks(1000, 3600).1, 3600);
+    }
+
+    #[test]
+    fn test_load() {
+        let path = std::env::temp_dir().join(format!("day16-keyring-{}.toml", rand::random::<u64>()));
+
+        // Never made up on the spot.
+        assert!(Keyring::load(&path, Algorithm::HS256).is_err());
+        assert!(!path.exists());
+
+        fs::write(&path, "").unwrap();
+        assert!(Keyring::load(&path, Algorithm::HS256).is_err());
+
+        fs::write(&path, "[[keys]]\nkid = ").unwrap();
+        assert!(Keyring::load(&path, Algorithm::HS256).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "[[keys]]\nkid = ");
+
+        fs::write(&path, generate(Algorithm::HS256, 0)).unwrap();
+        assert_eq!(Keyring::load(&path, Algorithm::HS256).unwrap().keys.len(), 1);
+        assert!(Keyring::load(&path, Algorithm::EdDSA).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Keyring::parse("keys = []").is_err());
+        assert!(Keyring::parse(r#"
+            [[keys]]
+            kid = "a"
+            secret = "c2hvcnQ"
+        "#).is_err());
+        assert!(Keyring::parse(&format!(r#"
+            [[keys]]
+            kid = "a"
+            alg = "RS256"
+            secret = "{SECRET_A}"
+        "#)).is_err());
+        assert!(Keyring::parse(&format!(r#"
+            [[keys]]
+            kid = "a"
+            secret = "{SECRET_A}"
+
+            [[keys]]
+            kid = "a"
+            secret = "{SECRET_B}"
+        "#)).is_err());
+    }
+}
@@ -0,0 +1,205 @@
+use jwt_simple::{claims::JWTClaims, reexports::ct_codecs::{Base64UrlSafeNoPadding, Encoder}};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sqlx::{query, query_as, types::Uuid, PgConnection};
+
+// Refresh tokens are opaque random values. Only their hashes are stored, and
+// each can be used once: redeeming one marks it used, and the holder gets the
+// next one of its family. A used token coming back means it was copied, so
+// the whole family is revoked, along with the gift tokens issued with it.
+
+// Why a refresh token was not accepted.
+#[derive(Debug)]
+pub(super) enum RefreshError {
+    // Never issued, or revoked.
+    Unknown,
+    Expired,
+    // Used before, which revoked its family.
+    Reused,
+    Database,
+}
+
+impl RefreshError {
+    pub(super) fn code(&self) -> &'static str {
+        match self {
+            RefreshError::Unknown => "unknown_refresh_token",
+            RefreshError::Expired => "expired",
+            RefreshError::Reused => "reused",
+            RefreshError::Database => "",
+        }
+    }
+}
+
+impl From<sqlx::Error> for RefreshError {
+    fn from(_: sqlx::Error) -> Self {
+        RefreshError::Database
+    }
+}
+
+// What a refresh token is good for.
+pub(super) struct Session {
+    pub(super) family: Uuid,
+    pub(super) payload: Value,
+}
+
+fn generate() -> String {
+    Base64UrlSafeNoPadding::encode_to_string(rand::random::<[u8; 32]>()).unwrap()
+}
+
+fn hash(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+// Issue the next refresh token of the family, along with the gift token with
+// the claims given, forgetting about those that expired already.
+pub(super) async fn issue(
+    conn: &mut PgConnection,
+    session: &Session,
+    lifetime: u64,
+    access: &JWTClaims<Value>,
+) -> Result<String, sqlx::Error> {
+    let token = generate();
+
+    query("DELETE FROM refresh_tokens WHERE expires_at < CURRENT_TIMESTAMP")
+        .execute(&mut *conn)
+        .await?;
+
+    query(r#"
+        INSERT INTO refresh_tokens (hash, family, payload, access_jti, access_expires_at, expires_at)
+        VALUES ($1, $2, $3, $4, to_timestamp($5), CURRENT_TIMESTAMP + make_interval(secs => $6))
+    "#)
+        .bind(hash(&token))
+        .bind(session.family)
+        .bind(&session.payload)
+        .bind(access.jwt_id.as_deref().unwrap_or_default())
+        .bind(access.expires_at.map_or(0.0, |exp| exp.as_secs() as f64))
+        .bind(lifetime as f64)
+        .execute(conn)
+        .await?;
+
+    Ok(token)
+}
+
+// Mark the token used, and respond with what it was issued for. Done in the
+// transaction of the caller, so that the token stays unused unless the next
+// one is issued in it too. If the token was used before, its family is
+// revoked instead, which the caller commits all the same.
+pub(super) async fn redeem(tx: &mut PgConnection, token: &str) -> Result<Session, RefreshError> {
+    let row: Option<(Uuid, Value, bool, bool)> = query_as(r#"
+        SELECT family, payload, used_at IS NOT NULL, expires_at < CURRENT_TIMESTAMP
+        FROM refresh_tokens
+        WHERE hash = $1
+        FOR UPDATE
+    "#)
+        .bind(hash(token))
+        .fetch_optional(&mut *tx)
+        .await?;
+
+    let (family, payload) = match row {
+        None => return Err(RefreshError::Unknown),
+        Some((family, _, true, _)) => {
+            revoke_family(tx, family).await?;
+
+            return Err(RefreshError::Reused)
+        },
+        Some((_, _, _, true)) => return Err(RefreshError::Expired),
+        Some((family, payload, _, _)) => (family, payload),
+    };
+
+    query("UPDATE refresh_tokens SET used_at = CURRENT_TIMESTAMP WHERE hash = $1")
+        .bind(hash(token))
+        .execute(tx)
+        .await?;
+
+    Ok(Session { family, payload })
+}
+
+// Revoke the family a gift token was issued with, if any, as on logout.
+pub(super) async fn revoke(conn: &mut PgConnection, access_jti: &str) -> Result<(), sqlx::Error> {
+    let family: Option<(Uuid,)> = query_as("SELECT family FROM refresh_tokens WHERE access_jti = $1")
+        .bind(access_jti)
+        .fetch_optional(&mut *conn)
+        .await?;
+
+    if let Some((family,)) = family {
+        revoke_family(conn, family).await?;
+    }
+
+    Ok(())
+}
+
+// Forget the refresh tokens of the family, and deny the gift tokens issued
+// with them until they expire.
+async fn revoke_family(conn: &mut PgConnection, family: Uuid) -> Result<(), sqlx::Error> {
+    query(r#"
+        INSERT INTO revoked_tokens (jti, expires_at)
+        SELECT access_jti, access_expires_at
+        FROM refresh_tokens
+        WHERE family = $1 AND access_expires_at > CURRENT_TIMESTAMP
+        ON CONFLICT (jti) DO NOTHING
+    "#)
+        .bind(family)
+        .execute(&mut *conn)
+        .await?;
+
+    query("DELETE FROM refresh_tokens WHERE family = $1")
+        .bind(family)
+        .execute(&mut *conn)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use jwt_simple::{claims::Claims, prelude::Duration};
+    use serde_json::json;
+    use sqlx::{query_scalar, PgPool};
+
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let token = generate();
+
+        assert_eq!(token.len(), 43);
+        assert_ne!(token, generate());
+        assert_eq!(hash(&token), hash(&token));
+        assert_ne!(hash(&token), hash(&generate()));
+    }
+
+    // Each run gets a database of its own, from the server at DATABASE_URL.
+    #[ignore = "needs Postgres at DATABASE_URL"]
+    #[sqlx::test]
+    async fn test_rotation(pool: PgPool) {
+        let mut conn = pool.acquire().await.unwrap();
+        let access = |jti: &str| Claims::with_custom_claims(Value::Null, Duration::from_secs(300)).with_jwt_id(jti);
+        let session = Session { family: Uuid::new_v4(), payload: json!({"gift": "socks"}) };
+        let first = issue(&mut conn, &session, 3600, &access("a")).await.unwrap();
+
+        let session = redeem(&mut conn, &first).await.unwrap();
+
+        assert_eq!(session.payload, json!({"gift": "socks"}));
+
+        let second = issue(&mut conn, &session, 3600, &access("b")).await.unwrap();
+
+        // Using the first again revokes the second, and the gift tokens of both.
+        assert!(matches!(redeem(&mut conn, &first).await, Err(RefreshError::Reused)));
+        assert!(matches!(redeem(&mut conn, &second).await, Err(RefreshError::Unknown)));
+
+        // As does revoking a gift token of the family.
+        let session = Session { family: Uuid::new_v4(), payload: json!({}) };
+        let third = issue(&mut conn, &session, 3600, &access("c")).await.unwrap();
+
+        revoke(&mut conn, "c").await.unwrap();
+
+        assert!(matches!(redeem(&mut conn, &third).await, Err(RefreshError::Unknown)));
+
+        let revoked: Vec<String> = query_scalar("SELECT jti FROM revoked_tokens ORDER BY jti")
+            .fetch_all(&mut *conn)
+            .await
+            .unwrap();
+
+        assert_eq!(revoked, ["a", "b", "c"]);
+    }
+}
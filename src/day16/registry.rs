@@ -0,0 +1,406 @@
+use std::{collections::HashSet, fs, io::ErrorKind, path::Path};
+
+use jwt_simple::{
+    claims::JWTClaims,
+    prelude::{
+        ECDSAP256PublicKeyLike, ECDSAP384PublicKeyLike, ES256PublicKey, ES384PublicKey,
+        EdDSAPublicKeyLike, Ed25519PublicKey, PS256PublicKey, PS384PublicKey, PS512PublicKey,
+        RS256PublicKey, RS384PublicKey, RS512PublicKey, RSAPublicKeyLike,
+    },
+    prelude::VerificationOptions,
+    reexports::ct_codecs::{Base64UrlSafeNoPadding, Decoder},
+    token::Token,
+};
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::config::ValidationPolicy;
+
+use super::validation::TokenError;
+
+const SANTA_KID: &str = "santa";
+
+const SANTA_PEM: &str = include_str!("../day16_santa_public_key.pem");
+
+// A public key, parsed once, for one algorithm. RSA keys serve all six RSA
+// algorithms, so they are in the registry six times.
+enum Verifier {
+    RS256(RS256PublicKey),
+    RS384(RS384PublicKey),
+    RS512(RS512PublicKey),
+    PS256(PS256PublicKey),
+    PS384(PS384PublicKey),
+    PS512(PS512PublicKey),
+    ES256(ES256PublicKey),
+    ES384(ES384PublicKey),
+    EdDSA(Ed25519PublicKey),
+}
+
+const ALGORITHMS: [&str; 9] = [
+    "RS256", "RS384", "RS512", "PS256", "PS384", "PS512", "ES256", "ES384", "EdDSA",
+];
+
+impl Verifier {
+    fn rsa(n: &[u8], e: &[u8]) -> Result<Vec<Verifier>, jwt_simple::Error> {
+        Ok(vec![
+            Verifier::RS256(RS256PublicKey::from_components(n, e)?),
+            Verifier::RS384(RS384PublicKey::from_components(n, e)?),
+            Verifier::RS512(RS512PublicKey::from_components(n, e)?),
+            Verifier::PS256(PS256PublicKey::from_components(n, e)?),
+            Verifier::PS384(PS384PublicKey::from_components(n, e)?),
+            Verifier::PS512(PS512PublicKey::from_components(n, e)?),
+        ])
+    }
+
+    // Whatever kind of key the PEM holds, for every algorithm it can serve.
+    fn from_pem(pem: &str) -> Option<Vec<Verifier>> {
+        if let Ok(key) = RS256PublicKey::from_pem(pem) {
+            let components = key.to_components();
+
+            Verifier::rsa(&components.n, &components.e).ok()
+        } else if let Ok(key) = ES256PublicKey::from_pem(pem) {
+            Some(vec![Verifier::ES256(key)])
+        } else if let Ok(key) = ES384PublicKey::from_pem(pem) {
+            Some(vec![Verifier::ES384(key)])
+        } else if let Ok(key) = Ed25519PublicKey::from_pem(pem) {
+            Some(vec![Verifier::EdDSA(key)])
+        } else {
+            None
+        }
+    }
+
+    fn algorithm(&self) -> &'static str {
+        match self {
+            Verifier::RS256(_) => "RS256",
+            Verifier::RS384(_) => "RS384",
+            Verifier::RS512(_) => "RS512",
+            Verifier::PS256(_) => "PS256",
+            Verifier::PS384(_) => "PS384",
+            Verifier::PS512(_) => "PS512",
+            Verifier::ES256(_) => "ES256",
+            Verifier::ES384(_) => "ES384",
+            Verifier::EdDSA(_) => "EdDSA",
+        }
+    }
+
+    fn verify(&self, jwt: &str, options: &VerificationOptions) -> Result<JWTClaims<Value>, jwt_simple::Error> {
+        let options = Some(options.clone());
+
+        match self {
+            Verifier::RS256(key) => key.verify_token(jwt, options),
+            Verifier::RS384(key) => key.verify_token(jwt, options),
+            Verifier::RS512(key) => key.verify_token(jwt, options),
+            Verifier::PS256(key) => key.verify_token(jwt, options),
+            Verifier::PS384(key) => key.verify_token(jwt, options),
+            Verifier::PS512(key) => key.verify_token(jwt, options),
+            Verifier::ES256(key) => key.verify_token(jwt, options),
+            Verifier::ES384(key) => key.verify_token(jwt, options),
+            Verifier::EdDSA(key) => key.verify_token(jwt, options),
+        }
+    }
+}
+
+// The parts of a JWK (RFC 7517) needed for public signature keys.
+#[derive(Deserialize)]
+struct Jwk {
+    kty: String,
+    kid: Option<String>,
+    alg: Option<String>,
+    #[serde(rename = "use")]
+    usage: Option<String>,
+    crv: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+    x: Option<String>,
+    y: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JwkFile {
+    Set { keys: Vec<Jwk> },
+    Key(Jwk),
+}
+
+fn base64url(field: &Option<String>, name: &str) -> Result<Vec<u8>, String> {
+    let value = field.as_ref().ok_or_else(|| format!("missing {}", name))?;
+
+    Base64UrlSafeNoPadding::decode_to_vec(value, None)
+        .map_err(|_| format!("{} is not base64url", name))
+}
+
+impl Jwk {
+    fn verifiers(&self) -> Result<Vec<Verifier>, String> {
+        if self.usage.as_ref().is_some_and(|usage| usage != "sig") {
+            return Err(String::from("not a signature key"));
+        }
+
+        let verifiers = match (self.kty.as_str(), self.crv.as_deref()) {
+            ("RSA", _) => {
+                Verifier::rsa(&base64url(&self.n, "n")?, &base64url(&self.e, "e")?)
+                    .map_err(|e| e.to_string())?
+            },
+            ("EC", Some("P-256")) | ("EC", Some("P-384")) => {
+                // SEC1 uncompressed point
+                let mut point = vec![4];
+
+                point.extend(base64url(&self.x, "x")?);
+                point.extend(base64url(&self.y, "y")?);
+
+                if self.crv.as_deref() == Some("P-256") {
+                    vec![Verifier::ES256(ES256PublicKey::from_bytes(&point).map_err(|e| e.to_string())?)]
+                } else {
+                    vec![Verifier::ES384(ES384PublicKey::from_bytes(&point).map_err(|e| e.to_string())?)]
+                }
+            },
+            ("OKP", Some("Ed25519")) => {
+                vec![Verifier::EdDSA(Ed25519PublicKey::from_bytes(&base64url(&self.x, "x")?).map_err(|e| e.to_string())?)]
+            },
+            (kty, crv) => return Err(format!("unsupported key type {} {}", kty, crv.unwrap_or(""))),
+        };
+
+        // A key may be restricted to one of the algorithms it could serve.
+        Ok(verifiers.into_iter()
+            .filter(|v| self.alg.as_ref().is_none_or(|alg| alg == v.algorithm()))
+            .collect())
+    }
+}
+
+// The public keys tokens posted to /16/decode may be signed with: Santa's,
+// plus those in the configured directory, as PEM files (named by the file
+// name without extension) or JWK / JWK set files ending in .json (named by
+// their kid, or else by the file name). A token with the kid of one of them
+// is only checked against that key, any other against every key for its
+// algorithm, since a kid we do not know says nothing about who signed it.
+pub(crate) struct KeyRegistry {
+    keys: Vec<(String, Verifier)>,
+}
+
+impl KeyRegistry {
+    pub(crate) fn load(dir: impl AsRef<Path>) -> Result<Self, String> {
+        let mut registry = KeyRegistry { keys: Vec::new() };
+
+        registry.add(SANTA_KID, Verifier::from_pem(SANTA_PEM).unwrap());
+
+        let entries = match fs::read_dir(dir.as_ref()) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(registry),
+            Err(e) => return Err(format!("{}: {}", dir.as_ref().display(), e)),
+        };
+
+        let mut paths = entries
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("{}: {}", dir.as_ref().display(), e))?;
+
+        paths.sort();
+
+        let mut kids = HashSet::from([String::from(SANTA_KID)]);
+
+        for path in paths {
+            let stem = path.file_stem().and_then(|s| s.to_str()).unwrap_or_default().to_string();
+            let keys = match path.extension().and_then(|e| e.to_str()) {
+                Some("pem") => KeyRegistry::read(&path)
+                    .and_then(|s| Verifier::from_pem(&s).ok_or_else(|| String::from("no public key")))
+                    .map(|verifiers| vec![(stem, verifiers)]),
+                Some("json") => KeyRegistry::read(&path)
+                    .and_then(|s| KeyRegistry::parse_jwks(&s, &stem)),
+                _ => continue,
+            }.map_err(|e| format!("{}: {}", path.display(), e))?;
+
+            for (kid, verifiers) in keys {
+                if !kids.insert(kid.clone()) {
+                    return Err(format!("{}: duplicate kid {}", path.display(), kid));
+                }
+
+                registry.add(&kid, verifiers);
+            }
+        }
+
+        Ok(registry)
+    }
+
+    fn read(path: &Path) -> Result<String, String> {
+        fs::read_to_string(path).map_err(|e| e.to_string())
+    }
+
+    fn parse_jwks(s: &str, stem: &str) -> Result<Vec<(String, Vec<Verifier>)>, String> {
+        let jwks = match serde_json::from_str(s).map_err(|e| e.to_string())? {
+            JwkFile::Set { keys } => keys,
+            JwkFile::Key(jwk) => vec![jwk],
+        };
+        let anonymous = jwks.iter().filter(|jwk| jwk.kid.is_none()).count();
+
+        jwks.iter().enumerate()
+            .map(|(i, jwk)| {
+                let kid = match &jwk.kid {
+                    Some(kid) => kid.clone(),
+                    None if anonymous == 1 => String::from(stem),
+                    None => format!("{}-{}", stem, i),
+                };
+
+                Ok((kid, jwk.verifiers()?))
+            })
+            .collect()
+    }
+
+    fn add(&mut self, kid: &str, verifiers: Vec<Verifier>) {
+        for verifier in verifiers {
+            self.keys.push((String::from(kid), verifier));
+        }
+    }
+
+    pub(super) fn verify(&self, jwt: &str, policy: &ValidationPolicy) -> Result<JWTClaims<Value>, TokenError> {
+        let metadata = Token::decode_metadata(jwt).map_err(|_| TokenError::Malformed)?;
+        let algorithm = metadata.algorithm();
+
+        if !ALGORITHMS.contains(&algorithm) {
+            return Err(TokenError::UnsupportedAlgorithm);
+        }
+
+        let known = metadata.key_id().filter(|key_id| self.keys.iter().any(|(kid, _)| kid == key_id));
+        let verifiers = self.keys.iter()
+            .filter(|(kid, v)| v.algorithm() == algorithm && known.is_none_or(|key_id| key_id == kid))
+            .map(|(_, v)| v);
+
+        policy.verify(verifiers, |verifier, options| verifier.verify(jwt, options))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use jwt_simple::{
+        claims::Claims,
+        prelude::{Clock, Duration, ECDSAP256KeyPairLike, ECDSAP384KeyPairLike, EdDSAKeyPairLike, ES256KeyPair, ES384KeyPair, Ed25519KeyPair},
+        reexports::ct_codecs::Encoder,
+    };
+    use serde_json::json;
+
+    use super::*;
+
+    fn claims() -> JWTClaims<Value> {
+        Claims::with_custom_claims(json!({"gift": "milk"}), Duration::from_hours(1))
+    }
+
+    fn dir(files: &[(&str, String)]) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("day16-registry-{}", rand::random::<u64>()));
+
+        fs::create_dir(&dir).unwrap();
+
+        for (name, content) in files {
+            fs::write(dir.join(name), content).unwrap();
+        }
+
+        dir
+    }
+
+    #[test]
+    fn test_santa() {
+        let registry = KeyRegistry::load("/nonexistent").unwrap();
+
+        assert_eq!(registry.keys.len(), 6);
+        assert!(registry.keys.iter().all(|(kid, _)| kid == SANTA_KID));
+
+        // Santa's key, but not signed by Santa
+        let es256 = ES256KeyPair::generate().with_key_id(SANTA_KID);
+        let jwt = es256.sign(claims()).unwrap();
+
+        assert_eq!(registry.verify(&jwt, &ValidationPolicy::default()).unwrap_err(), TokenError::UnknownKey);
+        assert_eq!(registry.verify("not a jwt", &ValidationPolicy::default()).unwrap_err(), TokenError::Malformed);
+
+        let rs256 = "eyJhbGciOiJSUzI1NiIsInR5cCI6IkpXVCJ9.eyJleHAiOjQxMDI0NDQ4MDB9.c2ln";
+
+        assert_eq!(registry.verify(rs256, &ValidationPolicy::default()).unwrap_err(), TokenError::InvalidSignature);
+
+        let hs256 = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.eyJleHAiOjQxMDI0NDQ4MDB9.c2ln";
+
+        assert_eq!(registry.verify(hs256, &ValidationPolicy::default()).unwrap_err(), TokenError::UnsupportedAlgorithm);
+    }
+
+    #[test]
+    fn test_directory() {
+        let es256 = ES256KeyPair::generate();
+        let es384 = ES384KeyPair::generate();
+        let ed25519 = Ed25519KeyPair::generate();
+        let other = Ed25519KeyPair::generate();
+        let jwk = json!({
+            "keys": [
+                {
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "kid": "ed",
+                    "use": "sig",
+                    "x": Base64UrlSafeNoPadding::encode_to_string(ed25519.public_key().to_bytes()).unwrap(),
+                },
+                {
+                    "kty": "OKP",
+                    "crv": "Ed25519",
+                    "x": Base64UrlSafeNoPadding::encode_to_string(other.public_key().to_bytes()).unwrap(),
+                },
+            ],
+        });
+        let dir = dir(&[
+            ("p256.pem", es256.public_key().to_pem().unwrap()),
+            ("p384.pem", es384.public_key().to_pem().unwrap()),
+            ("jwks.json", jwk.to_string()),
+            ("README", String::from("ignored")),
+        ]);
+        let registry = KeyRegistry::load(&dir).unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        let mut kids = registry.keys.iter()
+            .filter(|(kid, _)| kid != SANTA_KID)
+            .map(|(kid, v)| format!("{}:{}", kid, v.algorithm()))
+            .collect::<Vec<_>>();
+
+        kids.sort();
+        assert_eq!(kids, ["ed:EdDSA", "jwks:EdDSA", "p256:ES256", "p384:ES384"]);
+
+        let jwt = es256.with_key_id("p256").sign(claims()).unwrap();
+
+        assert_eq!(registry.verify(&jwt, &ValidationPolicy::default()).unwrap().custom, json!({"gift": "milk"}));
+
+        let jwt = es384.sign(claims()).unwrap();
+
+        assert_eq!(registry.verify(&jwt, &ValidationPolicy::default()).unwrap().custom, json!({"gift": "milk"}));
+
+        // Without a kid, every EdDSA key is tried.
+        let jwt = other.sign(claims()).unwrap();
+
+        assert!(registry.verify(&jwt, &ValidationPolicy::default()).is_ok());
+
+        let jwt = other.with_key_id("ed").sign(claims()).unwrap();
+
+        assert_eq!(registry.verify(&jwt, &ValidationPolicy::default()).unwrap_err(), TokenError::InvalidSignature);
+
+        // Nor with a kid that is not ours.
+        let jwt = es384.with_key_id("elsewhere").sign(claims()).unwrap();
+
+        assert!(registry.verify(&jwt, &ValidationPolicy::default()).is_ok());
+
+        let jwt = ed25519.sign(claims().invalid_before(Clock::now_since_epoch() + Duration::from_hours(1)))
+            .unwrap();
+
+        assert_eq!(registry.verify(&jwt, &ValidationPolicy::default()).unwrap_err(), TokenError::NotYetValid);
+    }
+
+    #[test]
+    fn test_rsa_jwk() {
+        let components = RS256PublicKey::from_pem(SANTA_PEM).unwrap().to_components();
+        let jwk = json!({
+            "kty": "RSA",
+            "alg": "PS512",
+            "n": Base64UrlSafeNoPadding::encode_to_string(components.n).unwrap(),
+            "e": Base64UrlSafeNoPadding::encode_to_string(components.e).unwrap(),
+        });
+        let keys = KeyRegistry::parse_jwks(&jwk.to_string(), "restricted").unwrap();
+
+        assert_eq!(keys.len(), 1);
+        assert_eq!(keys[0].0, "restricted");
+        assert_eq!(keys[0].1.iter().map(Verifier::algorithm).collect::<Vec<_>>(), ["PS512"]);
+
+        assert!(KeyRegistry::parse_jwks(r#"{"kty":"oct","k":"c2VjcmV0"}"#, "hmac").is_err());
+        assert!(KeyRegistry::parse_jwks(r#"{"kty":"OKP","crv":"Ed25519","use":"enc","x":""}"#, "enc").is_err());
+    }
+}
@@ -0,0 +1,217 @@
+use jwt_simple::{claims::JWTClaims, prelude::{Duration, VerificationOptions}, JWTError};
+use serde_json::Value;
+
+use crate::config::ValidationPolicy;
+
+// Why a token was not accepted. The code of each is what the endpoints
+// respond with.
+#[derive(Debug, PartialEq)]
+pub(crate) enum TokenError {
+    // No token at all.
+    Missing,
+    // Not a JWT.
+    Malformed,
+    // Signed with an algorithm none of the keys is for, or not signed.
+    UnsupportedAlgorithm,
+    // No key with the token's kid for its algorithm.
+    UnknownKey,
+    InvalidSignature,
+    Expired,
+    NotYetValid,
+    // Issued later than now, beyond the leeway.
+    IssuedInFuture,
+    // Issued longer ago than the maximum age.
+    TooOld,
+    InvalidIssuer,
+    InvalidAudience,
+    // A claim required by the policy is missing.
+    MissingClaim,
+    Revoked,
+    // Encrypted, but not with our key.
+    Undecryptable,
+    // Only signed, while encrypted tokens are required.
+    Unencrypted,
+    // Signed by one of the keys, but invalid otherwise.
+    Invalid,
+}
+
+impl TokenError {
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            TokenError::Missing => "missing_token",
+            TokenError::Malformed => "malformed",
+            TokenError::UnsupportedAlgorithm => "unsupported_algorithm",
+            TokenError::UnknownKey => "unknown_key",
+            TokenError::InvalidSignature => "invalid_signature",
+            TokenError::Expired => "expired",
+            TokenError::NotYetValid => "not_yet_valid",
+            TokenError::IssuedInFuture => "issued_in_future",
+            TokenError::TooOld => "too_old",
+            TokenError::InvalidIssuer => "invalid_issuer",
+            TokenError::InvalidAudience => "invalid_audience",
+            TokenError::MissingClaim => "missing_claim",
+            TokenError::Revoked => "revoked",
+            TokenError::Undecryptable => "undecryptable",
+            TokenError::Unencrypted => "unencrypted",
+            TokenError::Invalid => "invalid",
+        }
+    }
+}
+
+impl From<jwt_simple::Error> for TokenError {
+    fn from(e: jwt_simple::Error) -> Self {
+        match e.downcast::<JWTError>() {
+            Ok(JWTError::InvalidSignature | JWTError::InvalidAuthenticationTag) => TokenError::InvalidSignature,
+            Ok(JWTError::AlgorithmMismatch) => TokenError::UnknownKey,
+            Ok(JWTError::CompactEncodingError | JWTError::NotJWT | JWTError::TokenTooLong) => TokenError::Malformed,
+            Ok(JWTError::TokenHasExpired) => TokenError::Expired,
+            Ok(JWTError::TokenNotValidYet) => TokenError::NotYetValid,
+            Ok(JWTError::ClockDrift) => TokenError::IssuedInFuture,
+            Ok(JWTError::TokenIsTooOld) => TokenError::TooOld,
+            Ok(JWTError::RequiredIssuerMismatch | JWTError::RequiredIssuerMissing) => TokenError::InvalidIssuer,
+            Ok(JWTError::RequiredAudienceMismatch | JWTError::RequiredAudienceMissing) => TokenError::InvalidAudience,
+            _ => TokenError::Invalid,
+        }
+    }
+}
+
+impl ValidationPolicy {
+    pub(super) fn options(&self) -> VerificationOptions {
+        let set = |values: &Vec<String>| match values.is_empty() {
+            true => None,
+            false => Some(values.iter().cloned().collect()),
+        };
+
+        VerificationOptions {
+            allowed_issuers: set(&self.issuers),
+            allowed_audiences: set(&self.audiences),
+            max_validity: self.max_age.map(Duration::from_secs),
+            time_tolerance: Some(Duration::from_secs(self.leeway)),
+            ..Default::default()
+        }
+    }
+
+    // What the verification options cannot express.
+    fn check(&self, claims: &JWTClaims<Value>) -> Result<(), TokenError> {
+        // Without iat, the age of a token is unknown.
+        if self.max_age.is_some() && claims.issued_at.is_none() {
+            return Err(TokenError::MissingClaim);
+        }
+
+        if self.required_claims.iter().any(|claim| claims.custom.get(claim).is_none()) {
+            return Err(TokenError::MissingClaim);
+        }
+
+        Ok(())
+    }
+
+    // Verify a token with the candidate keys in turn, until one of them has
+    // signed it, and check it against the policy.
+    pub(super) fn verify<K>(
+        &self,
+        keys: impl IntoIterator<Item = K>,
+        verify: impl Fn(K, &VerificationOptions) -> Result<JWTClaims<Value>, jwt_simple::Error>,
+    ) -> Result<JWTClaims<Value>, TokenError> {
+        let options = self.options();
+        let mut result = Err(TokenError::UnknownKey);
+
+        for key in keys {
+            match verify(key, &options).map_err(TokenError::from) {
+                Ok(claims) => return self.check(&claims).map(|_| claims),
+                // Not signed with this key, try the next one.
+                Err(TokenError::UnknownKey) => {},
+                Err(TokenError::InvalidSignature) => result = Err(TokenError::InvalidSignature),
+                Err(e) => return Err(e),
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use jwt_simple::prelude::{Claims, Clock, HS256Key, MACLike};
+    use serde_json::json;
+
+    use super::*;
+
+    fn verify(policy: &ValidationPolicy, key: &HS256Key, claims: JWTClaims<Value>) -> Result<JWTClaims<Value>, TokenError> {
+        let jwt = key.authenticate(claims).unwrap();
+
+        policy.verify([key], |key, options| key.verify_token(&jwt, Some(options.clone())))
+    }
+
+    fn claims() -> JWTClaims<Value> {
+        Claims::with_custom_claims(json!({"gift": "cookie"}), Duration::from_hours(1))
+    }
+
+    #[test]
+    fn test_default_policy() {
+        let policy = ValidationPolicy::default();
+        let key = HS256Key::generate();
+
+        assert!(verify(&policy, &key, claims()).is_ok());
+        assert!(verify(&policy, &key, claims().with_issuer("anyone")).is_ok());
+
+        let mut expired = claims();
+
+        expired.expires_at = Some(Clock::now_since_epoch() - Duration::from_hours(1));
+        assert_eq!(verify(&policy, &key, expired).unwrap_err(), TokenError::Expired);
+
+        let future = claims().invalid_before(Clock::now_since_epoch() + Duration::from_hours(1));
+
+        assert_eq!(verify(&policy, &key, future).unwrap_err(), TokenError::NotYetValid);
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = ValidationPolicy {
+            issuers: vec![String::from("santa")],
+            audiences: vec![String::from("elves"), String::from("reindeer")],
+            required_claims: vec![String::from("gift")],
+            max_age: Some(60),
+            leeway: 0,
+        };
+        let key = HS256Key::generate();
+        let valid = || claims().with_issuer("santa").with_audience("reindeer");
+
+        assert!(verify(&policy, &key, valid()).is_ok());
+        assert_eq!(verify(&policy, &key, claims().with_audience("elves")).unwrap_err(), TokenError::InvalidIssuer);
+        assert_eq!(verify(&policy, &key, valid().with_issuer("grinch")).unwrap_err(), TokenError::InvalidIssuer);
+        assert_eq!(verify(&policy, &key, claims().with_issuer("santa")).unwrap_err(), TokenError::InvalidAudience);
+        assert_eq!(verify(&policy, &key, valid().with_audience("grinch")).unwrap_err(), TokenError::InvalidAudience);
+
+        let mut no_gift = valid();
+
+        no_gift.custom = json!({"coal": true});
+        assert_eq!(verify(&policy, &key, no_gift).unwrap_err(), TokenError::MissingClaim);
+
+        let mut no_iat = valid();
+
+        no_iat.issued_at = None;
+        assert_eq!(verify(&policy, &key, no_iat).unwrap_err(), TokenError::MissingClaim);
+
+        let mut old = valid();
+
+        old.issued_at = Some(Clock::now_since_epoch() - Duration::from_secs(120));
+        assert_eq!(verify(&policy, &key, old).unwrap_err(), TokenError::TooOld);
+
+        let mut early = valid();
+
+        early.issued_at = Some(Clock::now_since_epoch() + Duration::from_secs(120));
+        assert_eq!(verify(&policy, &key, early).unwrap_err(), TokenError::IssuedInFuture);
+    }
+
+    #[test]
+    fn test_keys() {
+        let policy = ValidationPolicy::default();
+        let keys = [HS256Key::generate(), HS256Key::generate()];
+        let jwt = keys[1].authenticate(claims()).unwrap();
+        let verify = |key: &HS256Key, options: &VerificationOptions| key.verify_token(&jwt, Some(options.clone()));
+
+        assert!(policy.verify(&keys, verify).is_ok());
+        assert_eq!(policy.verify(&keys[..1], verify).unwrap_err(), TokenError::InvalidSignature);
+        assert_eq!(policy.verify(&keys[..0], verify).unwrap_err(), TokenError::UnknownKey);
+    }
+}
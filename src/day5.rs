@@ -71,7 +71,7 @@ pub(super) async fn manifest(
                         .map(|order| format!("{}: {}", order.item, order.quantity))
                         .collect::<Vec<_>>();
 
-                    if orders.len() > 0 {
+                    if !orders.is_empty() {
                         return (StatusCode::OK, orders.join("\n"));
                     } 
                 }
@@ -1,105 +1,328 @@
-use axum::{extract::State, http::{self, HeaderMap, StatusCode}, response::IntoResponse, Json};
-use leaky_bucket::RateLimiter;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, Query, State},
+    http::{self, HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
 use tokio::time::Duration;
 
-use crate::AppState;
+use crate::{config::{RateLimitKey, Tier}, AppState};
+
+use bucket::{hash_api_key, Client};
+pub(super) use bucket::MilkBuckets;
+pub(super) use layer::{Policy, RateLimitLayer};
+pub(super) use telemetry::Telemetry;
+
+mod bucket;
+mod layer;
+mod postgres;
+mod telemetry;
+mod units;
 
 const MILK_WITHDRAWN: &str = "Milk withdrawn\n";
 
 const NO_MILK_AVAILABLE: &str = "No milk available\n";
 
-pub(super) struct MilkBucket(RateLimiter);
+// The longest a request to /9/milk may wait for milk.
+const MAX_WAIT: Duration = Duration::from_secs(30);
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers.get(http::header::CONTENT_TYPE).is_some_and(|v| v == "application/json")
+}
+
+// Liters a request to /9/milk asks for: the positive number of "units" in
+// its JSON body, or else one. None if /9/milk would turn the body away, so
+// that no milk is withdrawn for it.
+fn milk_cost(headers: &HeaderMap, body: &[u8]) -> Option<usize> {
+    if !is_json(headers) {
+        return Some(1);
+    }
+
+    let mut request = serde_json::from_slice::<Map<String, Value>>(body).ok()?;
+    let units = request.remove("units");
+
+    // As milk converts it.
+    if units.is_none() || !request.is_empty() {
+        units::convert(&request, None).ok()?;
+    }
 
-impl MilkBucket {
-    pub(super) fn new() -> MilkBucket {
-        MilkBucket(RateLimiter::builder()
-                .initial(5)
-                .max(5)
-                .interval(Duration::from_millis(1000))
-                .build())
+    match units {
+        Some(units) => units.as_u64().filter(|units| *units > 0).map(|units| units as usize),
+        None => Some(1),
     }
 }
 
-#[derive(serde::Serialize, serde::Deserialize)]
-pub(super) struct MilkUnit {
-    #[serde(skip_serializing_if = "Option::is_none")]
-    liters: Option<f32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    gallons: Option<f32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    litres: Option<f32>,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pints: Option<f32>,
+// The limit of /9/milk: the liters asked for from the bucket of the client,
+// waiting in line for them with ?wait=<ms>. Those who time out are told their
+// place in line, and more than the bucket holds is too large a request.
+pub(super) fn milk_limit(buckets: Arc<MilkBuckets>, telemetry: Arc<Telemetry>) -> RateLimitLayer {
+    let policy = Policy::new(buckets, RateLimitKey::Client)
+        .with_rejection(|shortage| {
+            let body = match shortage.position {
+                Some(position) => format!("{}Position in line: {}\n", NO_MILK_AVAILABLE, position),
+                None => String::from(NO_MILK_AVAILABLE),
+            };
+
+            (StatusCode::TOO_MANY_REQUESTS, body).into_response()
+        })
+        .with_cost(milk_cost)
+        .with_waiting(MAX_WAIT)
+        .with_telemetry(telemetry);
+
+    RateLimitLayer::new(policy)
 }
 
+// POST /9/milk: Once the milk is withdrawn by the milk limit, convert the
+// amount in the body between units, if any.
 pub(super) async fn milk(
     headers: HeaderMap,
-    State(state): State<AppState>,
-    milk_unit: Option<Json<MilkUnit>>,
+    request: Option<Json<Map<String, Value>>>,
 ) -> impl IntoResponse {
-    if !state.milk_bucket.read().await.0.try_acquire(1) {
-        return (
-            StatusCode::TOO_MANY_REQUESTS,
-            String::from(NO_MILK_AVAILABLE),
-        );
+    if !is_json(&headers) {
+        return (StatusCode::OK, String::from(MILK_WITHDRAWN));
     }
 
-    if !headers.get(http::header::CONTENT_TYPE)
-        .is_some_and(|v| v == "application/json") {
-        return (
-                StatusCode::OK,
-                String::from(MILK_WITHDRAWN),
-        );
+    let Some(Json(mut request)) = request else {
+        return (StatusCode::BAD_REQUEST, String::new());
+    };
+
+    // Already charged, and nothing to convert unless there is more.
+    if request.remove("units").is_some() && request.is_empty() {
+        return (StatusCode::OK, String::from(MILK_WITHDRAWN));
     }
 
-    if let Some(u) = milk_unit {
-        match (u.gallons, u.liters, u.litres, u.pints) {
-            (Some(gallons), None, None, None) => Some(MilkUnit {
-                gallons: None,
-                liters: Some(gallons * 3.78541),
-                litres: None,
-                pints: None,
-            }),
-            (None, Some(liters), None, None) => Some(MilkUnit {
-                gallons: Some(liters / 3.78541),
-                liters: None,
-                litres: None,
-                pints: None,
-            }),
-            (None, None, Some(litres), None) => Some(MilkUnit {
-                gallons: None,
-                liters: None,
-                litres: None,
-                pints: Some(litres * 1.7598),
-            }),
-            (None, None, None, Some(pints)) => Some(MilkUnit {
-                gallons: None,
-                liters: None,
-                litres: Some(pints / 1.7598),
-                pints: None,
-            }),
-            _ => None,
+    match units::convert(&request, None) {
+        Ok(response) => (StatusCode::OK, serde_json::to_string(&response).unwrap()),
+        Err(_) => (StatusCode::BAD_REQUEST, String::new()),
+    }
+}
+
+// As many decimal places as an f64 has to spare.
+const MAX_PRECISION: u32 = 15;
+
+#[derive(Deserialize)]
+pub(super) struct Precision {
+    // Decimal places to round to.
+    precision: Option<u32>,
+}
+
+// POST /9/convert: Convert each amount of a JSON array, or of the lines of
+// application/x-ndjson, like /9/milk does, and respond with the results in
+// order. Those that cannot be converted get {"error": <code>} in their place.
+// The milk bucket does not limit it, a rate_limits entry can.
+pub(super) async fn convert(
+    headers: HeaderMap,
+    Query(query): Query<Precision>,
+    body: Bytes,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    if query.precision.is_some_and(|precision| precision > MAX_PRECISION) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let content_type = headers.get(http::header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+
+    // None for those that are not objects.
+    let requests: Vec<Option<Map<String, Value>>> = match content_type {
+        Some("application/json") => serde_json::from_slice::<Vec<Value>>(&body)
+            .map_err(|_| StatusCode::BAD_REQUEST)?
+            .into_iter()
+            .map(|request| match request {
+                Value::Object(request) => Some(request),
+                _ => None,
+            })
+            .collect(),
+        Some("application/x-ndjson") => body.split(|b| *b == b'\n')
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .map(|line| serde_json::from_slice(line).ok())
+            .collect(),
+        _ => return Err(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+    };
+
+    let results: Vec<Value> = requests.iter()
+        .map(|request| match request.as_ref().map(|request| units::convert(request, query.precision)) {
+            Some(Ok(converted)) => Value::Object(converted),
+            Some(Err(e)) => json!({"error": e.code()}),
+            None => json!({"error": "invalid"}),
+        })
+        .collect();
+
+    Ok(Json(results))
+}
+
+// A client given by its IP address or API key, or none.
+#[derive(Deserialize)]
+pub(super) struct ClientQuery {
+    ip: Option<IpAddr>,
+    api_key: Option<String>,
+}
+
+impl ClientQuery {
+    fn client(self) -> Result<Option<Client>, StatusCode> {
+        match (self.ip, self.api_key) {
+            (None, None) => Ok(None),
+            (Some(ip), None) => Ok(Some(Client::Ip(ip))),
+            (None, Some(key)) => Ok(Some(Client::ApiKey(hash_api_key(&key)))),
+            (Some(_), Some(_)) => Err(StatusCode::BAD_REQUEST),
         }
-        .map_or(
-            (StatusCode::BAD_REQUEST, String::new()),
-            |milk_unit| (StatusCode::OK, serde_json::to_string(&milk_unit).unwrap())
-        )
-    } else {
-        (StatusCode::BAD_REQUEST, String::new())
     }
 }
 
+// POST /9/refill: Fill up the bucket of the client with the given IP address
+// or API key, or else those of all clients.
 pub(super) async fn refill(
     State(state): State<AppState>,
+    Query(query): Query<ClientQuery>,
+) -> impl IntoResponse {
+    let client = match query.client() {
+        Ok(client) => client,
+        Err(status) => return status,
+    };
+
+    match state.milk_buckets.refill(client.as_ref()).await {
+        Ok(()) => StatusCode::OK,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+// GET /9/status: How much milk is left in the bucket of the client with the
+// given IP address or API key, or else in that of the caller.
+pub(super) async fn status(
+    State(state): State<AppState>,
+    Query(query): Query<ClientQuery>,
+    headers: HeaderMap,
+    peer: Option<ConnectInfo<SocketAddr>>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    let client = match query.client()? {
+        Some(client) => client,
+        None => state.milk_buckets.identify(&headers, peer.map(|ConnectInfo(addr)| addr.ip()))
+            .ok_or(StatusCode::BAD_REQUEST)?,
+    };
+    let level = state.milk_buckets.level(&client)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok::<_, StatusCode>(Json(json!({
+        "capacity": level.capacity,
+        "remaining": level.remaining,
+        // In milliseconds, null when full.
+        "next_refill": level.next_refill.map(|d| d.as_millis() as u64),
+    })))
+}
+
+#[derive(Deserialize)]
+pub(super) struct StatsQuery {
+    // In seconds.
+    #[serde(default = "default_window")]
+    window: u64,
+    #[serde(default = "default_top")]
+    top: usize,
+}
+
+fn default_window() -> u64 {
+    60 * 60
+}
+
+fn default_top() -> usize {
+    10
+}
+
+// GET /9/stats: The withdrawals from the milk buckets granted and refused in
+// the last hour, or the window given, per minute, and the clients who
+// withdrew the most. Only as far back as the telemetry goes.
+pub(super) async fn stats(
+    State(state): State<AppState>,
+    Query(query): Query<StatsQuery>,
 ) -> impl IntoResponse {
-    let _ = std::mem::replace(
-        &mut state.milk_bucket.write().await.0,
-        RateLimiter::builder()
-            .initial(5)
-            .max(5)
-            .interval(Duration::from_millis(1000))
-            .build()
-    );
-
-    StatusCode::OK
-}
\ No newline at end of file
+    Json(state.milk_telemetry.stats(query.window, query.top))
+}
+
+#[derive(Deserialize)]
+pub(super) struct TierQuery {
+    #[serde(default = "default_tier")]
+    tier: String,
+}
+
+fn default_tier() -> String {
+    String::from(bucket::DEFAULT_TIER)
+}
+
+// The settings to change, the others are kept.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(super) struct TierUpdate {
+    capacity: Option<usize>,
+    refill: Option<usize>,
+    interval: Option<u64>,
+}
+
+// GET /9/config: The settings of the milk tiers, by name.
+pub(super) async fn config(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.milk_buckets.tiers())
+}
+
+// PUT /9/config: Change the settings of the default milk tier, or the one
+// given, for the buckets already in use too. They keep their milk, up to the
+// new capacity. Only for the buckets in memory: with the postgres backend,
+// the other instances would keep the tiers of config.toml, so it is a 409.
+pub(super) async fn configure(
+    State(state): State<AppState>,
+    Query(query): Query<TierQuery>,
+    Json(update): Json<TierUpdate>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    if state.milk_buckets.is_shared() {
+        return Err((
+            StatusCode::CONFLICT,
+            String::from("milk tiers of shared buckets are only set in config.toml"),
+        ));
+    }
+
+    let current = match state.milk_buckets.tiers().remove(&query.tier) {
+        Some(tier) => tier,
+        None => return Err((StatusCode::NOT_FOUND, format!("no {} milk tier", query.tier))),
+    };
+
+    let tier = Tier {
+        capacity: update.capacity.unwrap_or(current.capacity),
+        refill: update.refill.unwrap_or(current.refill),
+        interval: update.interval.unwrap_or(current.interval),
+    };
+
+    state.milk_buckets.configure(&query.tier, tier)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    Ok(Json(tier))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_milk_cost() {
+        let mut json = HeaderMap::new();
+
+        json.insert(http::header::CONTENT_TYPE, "application/json".parse().unwrap());
+
+        assert_eq!(milk_cost(&HeaderMap::new(), br#"{"units": 3}"#), Some(1));
+        assert_eq!(milk_cost(&json, br#"{"units": 3}"#), Some(3));
+        assert_eq!(milk_cost(&json, br#"{"gallons": 2, "units": 2}"#), Some(2));
+        assert_eq!(milk_cost(&json, br#"{"gallons": 2}"#), Some(1));
+        assert_eq!(milk_cost(&json, br#"{"liters": 1, "note": "x"}"#), Some(1));
+        // Nothing withdrawn for what cannot be converted.
+        assert_eq!(milk_cost(&json, b"not json"), None);
+        assert_eq!(milk_cost(&json, br#"{}"#), None);
+        assert_eq!(milk_cost(&json, br#"{"gallons": 2, "liters": 1}"#), None);
+        assert_eq!(milk_cost(&json, br#"{"units": 2, "hogsheads": 1}"#), None);
+        assert_eq!(milk_cost(&json, br#"{"units": 0}"#), None);
+        assert_eq!(milk_cost(&json, br#"{"units": 1.5}"#), None);
+        assert_eq!(milk_cost(&json, br#"{"units": "3"}"#), None);
+    }
+}
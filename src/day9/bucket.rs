@@ -0,0 +1,880 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex, RwLock},
+};
+
+use axum::http::HeaderMap;
+use dashmap::DashMap;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use tokio::{
+    sync::Notify,
+    time::{self, Duration, Instant},
+};
+
+use crate::config::{MilkConfig, Tier};
+
+use super::postgres;
+
+pub(super) const DEFAULT_TIER: &str = "default";
+
+// The most liters a tier can hold or add at once.
+const MAX_LITERS: usize = 1_000_000_000;
+
+// The longest a bucket of any tier can take to fill up from empty.
+const MAX_FILL_TIME: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// Who is withdrawing milk, each with a bucket of their own.
+#[derive(Clone, PartialEq, Eq, Hash, Debug)]
+pub(crate) enum Client {
+    // By the hex SHA-256 of one of the configured API keys.
+    ApiKey(String),
+    Ip(IpAddr),
+    // All of them, for limits that everyone shares.
+    Everyone,
+}
+
+// As in "ip:10.0.0.1" or "api_key:<hex SHA-256 of the key>".
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Client::ApiKey(hash) => write!(f, "api_key:{}", hash),
+            Client::Ip(ip) => write!(f, "ip:{}", ip),
+            Client::Everyone => write!(f, "everyone"),
+        }
+    }
+}
+
+pub(super) fn hash_api_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+// The client by its API key if it is a known one, or else by its address:
+// the X-Forwarded-For hop added by the first of the trusted proxies, or the
+// peer address if there are none. None if there is no such address, rather
+// than lumping everyone without one together.
+fn identify(
+    headers: &HeaderMap,
+    peer: Option<IpAddr>,
+    api_keys: &HashMap<String, String>,
+    trusted_proxies: usize,
+) -> Option<Client> {
+    let api_key = headers.get("x-api-key")
+        .and_then(|key| key.to_str().ok())
+        .map(hash_api_key)
+        .filter(|hash| api_keys.contains_key(hash));
+
+    if let Some(hash) = api_key {
+        return Some(Client::ApiKey(hash));
+    }
+
+    if trusted_proxies == 0 {
+        return peer.map(Client::Ip);
+    }
+
+    // Those before are whatever the client said.
+    let hops = headers.get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+
+    hops.len()
+        .checked_sub(trusted_proxies)
+        .and_then(|i| hops[i].trim().parse().ok())
+        .map(Client::Ip)
+}
+
+// A token bucket of milk: it holds up to the capacity of its tier, and is
+// refilled every interval while it is not full. Those waiting for milk are
+// served in turn.
+pub(super) struct MilkBucket {
+    balance: Mutex<Balance>,
+    // Wakes up the line when the milk or the line changes.
+    notify: Notify,
+}
+
+struct Balance {
+    tier: Tier,
+    liters: usize,
+    // When the bucket was last refilled, or found full.
+    refilled_at: Instant,
+    // The tickets of those waiting and the liters they want, the first in
+    // line at the front.
+    line: VecDeque<(u64, usize)>,
+    next_ticket: u64,
+}
+
+// How much milk is in a bucket.
+#[derive(PartialEq, Debug)]
+pub(crate) struct Level {
+    pub(super) capacity: usize,
+    pub(super) remaining: usize,
+    // None if the bucket is full.
+    pub(super) next_refill: Option<Duration>,
+}
+
+// Not enough milk for a withdrawal, for now.
+#[derive(PartialEq, Debug)]
+pub(crate) struct Shortage {
+    pub(super) level: Level,
+    // Until there is enough for it after what those ahead in line want, if
+    // nobody else takes any.
+    pub(super) retry_after: Duration,
+    // The place in line from 1, if it waited in it.
+    pub(super) position: Option<usize>,
+}
+
+// Why milk was not withdrawn.
+#[derive(PartialEq, Debug)]
+pub(super) enum Withheld {
+    Empty(Shortage),
+    // More than the bucket ever holds, so not worth waiting for.
+    TooMuch(Level),
+    Database,
+}
+
+impl From<sqlx::Error> for Withheld {
+    fn from(_: sqlx::Error) -> Self {
+        Withheld::Database
+    }
+}
+
+impl Level {
+    pub(super) fn full(tier: &Tier) -> Level {
+        Level {
+            capacity: tier.capacity,
+            remaining: tier.capacity,
+            next_refill: None,
+        }
+    }
+}
+
+// That many intervals, or Duration::MAX if they last longer than that.
+fn intervals(interval: Duration, n: usize) -> Duration {
+    u32::try_from(n).ok()
+        .and_then(|n| interval.checked_mul(n))
+        .unwrap_or(Duration::MAX)
+}
+
+// How long a bucket of the tier takes to fill up from empty.
+fn fill_time(tier: &Tier) -> Duration {
+    intervals(Duration::from_millis(tier.interval), tier.capacity.div_ceil(tier.refill))
+}
+
+impl Balance {
+    fn interval(&self) -> Duration {
+        Duration::from_millis(self.tier.interval)
+    }
+
+    fn fill_time(&self) -> Duration {
+        fill_time(&self.tier)
+    }
+
+    // Add the milk of the intervals that passed since the last refill.
+    fn refill(&mut self, now: Instant) {
+        let interval = self.interval();
+        // Any more than that many intervals fill it up anyway.
+        let intervals = (now.saturating_duration_since(self.refilled_at).as_millis() / interval.as_millis())
+            .min(self.tier.capacity.div_ceil(self.tier.refill) as u128) as usize;
+
+        self.liters = self.tier.capacity.min(self.liters + intervals * self.tier.refill);
+        self.refilled_at += interval * intervals as u32;
+
+        if self.liters == self.tier.capacity {
+            self.refilled_at = now;
+        }
+    }
+
+    fn level(&self, now: Instant) -> Level {
+        Level {
+            capacity: self.tier.capacity,
+            remaining: self.liters,
+            next_refill: (self.liters < self.tier.capacity)
+                .then(|| (self.refilled_at + self.interval()).saturating_duration_since(now)),
+        }
+    }
+
+    // When there will be enough milk for the liters, if nobody else takes
+    // any, unless that is too far away to tell.
+    fn enough_at(&self, liters: usize) -> Option<Instant> {
+        let n = liters.saturating_sub(self.liters).div_ceil(self.tier.refill);
+
+        self.refilled_at.checked_add(intervals(self.interval(), n))
+    }
+
+    // The place of the ticket in line, from 1.
+    fn position(&self, ticket: u64) -> usize {
+        self.line.iter().position(|(t, _)| *t == ticket).map_or(0, |i| i + 1)
+    }
+
+    // The liters wanted by the first so many in line.
+    fn demand(&self, first: usize) -> usize {
+        self.line.iter().take(first).map(|(_, liters)| liters).sum()
+    }
+
+    // Short of milk for the demand, served in this order.
+    fn shortage(&self, demand: usize, position: Option<usize>, now: Instant) -> Shortage {
+        Shortage {
+            level: self.level(now),
+            retry_after: self.enough_at(demand).map_or(Duration::MAX, |at| at.saturating_duration_since(now)),
+            position,
+        }
+    }
+}
+
+// A place in the line of a bucket, given up when dropped, on timing out or
+// when the request is cancelled.
+struct Place<'a> {
+    bucket: &'a MilkBucket,
+    ticket: u64,
+}
+
+impl Drop for Place<'_> {
+    fn drop(&mut self) {
+        self.bucket.balance.lock().unwrap().line.retain(|(t, _)| *t != self.ticket);
+        self.bucket.notify.notify_waiters();
+    }
+}
+
+impl MilkBucket {
+    fn new(tier: &Tier) -> MilkBucket {
+        MilkBucket {
+            balance: Mutex::new(Balance {
+                tier: *tier,
+                liters: tier.capacity,
+                refilled_at: Instant::now(),
+                line: VecDeque::new(),
+                next_ticket: 0,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    // Withdraw the liters if there is enough milk and nobody waiting for it,
+    // and respond with what is left either way.
+    pub(super) fn try_withdraw(&self, liters: usize) -> Result<Level, Withheld> {
+        self.try_withdraw_at(liters, Instant::now())
+    }
+
+    fn try_withdraw_at(&self, liters: usize, now: Instant) -> Result<Level, Withheld> {
+        let mut balance = self.balance.lock().unwrap();
+
+        balance.refill(now);
+
+        if liters > balance.tier.capacity {
+            return Err(Withheld::TooMuch(balance.level(now)));
+        }
+
+        if balance.liters < liters || !balance.line.is_empty() {
+            // After the whole line.
+            let demand = balance.demand(balance.line.len()) + liters;
+
+            return Err(Withheld::Empty(balance.shortage(demand, None, now)));
+        }
+
+        balance.liters -= liters;
+
+        Ok(balance.level(now))
+    }
+
+    // Withdraw the liters, waiting in line for at most the timeout until
+    // there is enough milk. On timing out, respond with what is left and the
+    // place in line. More than the capacity never comes, so that is not
+    // waited for.
+    pub(super) async fn withdraw(&self, liters: usize, timeout: Duration) -> Result<Level, Withheld> {
+        let deadline = Instant::now() + timeout;
+
+        let place = {
+            let mut balance = self.balance.lock().unwrap();
+            let now = Instant::now();
+
+            balance.refill(now);
+
+            if liters > balance.tier.capacity {
+                return Err(Withheld::TooMuch(balance.level(now)));
+            }
+
+            let ticket = balance.next_ticket;
+
+            balance.next_ticket += 1;
+            balance.line.push_back((ticket, liters));
+
+            Place { bucket: self, ticket }
+        };
+
+        loop {
+            // Registered before looking, so that no change is missed.
+            let notified = self.notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let wake_at = {
+                let mut balance = self.balance.lock().unwrap();
+                let now = Instant::now();
+
+                balance.refill(now);
+
+                let position = balance.position(place.ticket);
+
+                if position == 1 && liters <= balance.liters {
+                    balance.liters -= liters;
+                    balance.line.pop_front();
+
+                    return Ok(balance.level(now));
+                }
+
+                // Since reconfigured.
+                if liters > balance.tier.capacity {
+                    return Err(Withheld::TooMuch(balance.level(now)));
+                }
+
+                if now >= deadline {
+                    return Err(Withheld::Empty(balance.shortage(balance.demand(position), Some(position), now)));
+                }
+
+                match position {
+                    1 => balance.enough_at(liters).map_or(deadline, |at| at.min(deadline)),
+                    _ => deadline,
+                }
+            };
+
+            tokio::select! {
+                _ = notified => {},
+                _ = time::sleep_until(wake_at) => {},
+            }
+        }
+    }
+
+    fn level(&self, now: Instant) -> Level {
+        let mut balance = self.balance.lock().unwrap();
+
+        balance.refill(now);
+        balance.level(now)
+    }
+
+    // Switch to another tier, keeping the milk that fits.
+    fn reconfigure(&self, tier: &Tier, now: Instant) {
+        {
+            let mut balance = self.balance.lock().unwrap();
+
+            balance.refill(now);
+            balance.tier = *tier;
+            balance.liters = balance.liters.min(tier.capacity);
+
+            // Refilled from now on at the new rate.
+            balance.refilled_at = now;
+        }
+
+        self.notify.notify_waiters();
+    }
+
+    fn fill_up(&self, now: Instant) {
+        {
+            let mut balance = self.balance.lock().unwrap();
+
+            balance.liters = balance.tier.capacity;
+            balance.refilled_at = now;
+        }
+
+        self.notify.notify_waiters();
+    }
+
+    fn fill_time(&self) -> Duration {
+        self.balance.lock().unwrap().fill_time()
+    }
+}
+
+struct Entry {
+    bucket: Arc<MilkBucket>,
+    tier: String,
+    last_used: Instant,
+}
+
+// Where the milk is kept.
+enum Store {
+    // In the buckets of this instance.
+    Memory,
+    // In rows shared by the instances.
+    Postgres(PgPool),
+}
+
+pub(crate) struct MilkBuckets {
+    store: Store,
+    buckets: DashMap<Client, Entry>,
+    tiers: RwLock<HashMap<String, Tier>>,
+    api_keys: HashMap<String, String>,
+    trusted_proxies: usize,
+    idle_timeout: Duration,
+    last_sweep: Mutex<Instant>,
+}
+
+fn validate(name: &str, tier: &Tier) -> Result<(), String> {
+    if tier.capacity == 0 || tier.refill == 0 || tier.interval == 0 {
+        return Err(format!("milk tier {} never holds any milk", name));
+    }
+
+    if tier.capacity > MAX_LITERS || tier.refill > MAX_LITERS {
+        return Err(format!("milk tier {} holds more than {} liters", name, MAX_LITERS));
+    }
+
+    if fill_time(tier) > MAX_FILL_TIME {
+        return Err(format!("milk tier {} takes longer than {:?} to fill up", name, MAX_FILL_TIME));
+    }
+
+    Ok(())
+}
+
+impl MilkBuckets {
+    pub(crate) fn new(config: &MilkConfig) -> Result<MilkBuckets, String> {
+        if !config.tiers.contains_key(DEFAULT_TIER) {
+            return Err(format!("no {} milk tier", DEFAULT_TIER));
+        }
+
+        for (name, tier) in &config.tiers {
+            validate(name, tier)?;
+        }
+
+        if let Some(tier) = config.api_keys.values().find(|tier| !config.tiers.contains_key(*tier)) {
+            return Err(format!("no {} milk tier", tier));
+        }
+
+        Ok(MilkBuckets {
+            store: Store::Memory,
+            buckets: DashMap::new(),
+            tiers: RwLock::new(config.tiers.clone()),
+            api_keys: config.api_keys.clone(),
+            trusted_proxies: config.trusted_proxies,
+            idle_timeout: Duration::from_secs(config.idle_timeout),
+            last_sweep: Mutex::new(Instant::now()),
+        })
+    }
+
+    // The same buckets, kept in Postgres so that the instances share them.
+    pub(crate) fn shared(self, pool: PgPool) -> MilkBuckets {
+        MilkBuckets {
+            store: Store::Postgres(pool),
+            ..self
+        }
+    }
+
+    // Whether the other instances share these buckets, each with the tiers
+    // of its own config.toml.
+    pub(super) fn is_shared(&self) -> bool {
+        matches!(self.store, Store::Postgres(_))
+    }
+
+    // The name and settings of the tier of the client.
+    fn tier(&self, client: &Client) -> (String, Tier) {
+        let name = match client {
+            Client::ApiKey(hash) => self.api_keys.get(hash).map_or(DEFAULT_TIER, String::as_str),
+            _ => DEFAULT_TIER,
+        };
+
+        (String::from(name), self.tiers.read().unwrap()[name])
+    }
+
+    // The capacity of the bucket of the client, or of the default tier.
+    pub(super) fn capacity(&self, client: Option<&Client>) -> usize {
+        match client {
+            Some(client) => self.tier(client).1.capacity,
+            None => self.tiers.read().unwrap()[DEFAULT_TIER].capacity,
+        }
+    }
+
+    pub(super) fn tiers(&self) -> HashMap<String, Tier> {
+        self.tiers.read().unwrap().clone()
+    }
+
+    // Change the settings of a tier, for the buckets in it too, which keep
+    // their milk up to the new capacity.
+    pub(super) fn configure(&self, name: &str, tier: Tier) -> Result<(), String> {
+        validate(name, &tier)?;
+
+        match self.tiers.write().unwrap().get_mut(name) {
+            Some(current) => *current = tier,
+            None => return Err(format!("no {} milk tier", name)),
+        }
+
+        // A bucket being added with the old settings meanwhile is in the map
+        // by the time the iteration gets to its shard.
+        let now = Instant::now();
+
+        for entry in self.buckets.iter().filter(|entry| entry.tier == name) {
+            entry.bucket.reconfigure(&tier, now);
+        }
+
+        Ok(())
+    }
+
+    // The client by the API keys of these buckets, or its address.
+    pub(super) fn identify(&self, headers: &HeaderMap, peer: Option<IpAddr>) -> Option<Client> {
+        identify(headers, peer, &self.api_keys, self.trusted_proxies)
+    }
+
+    // Withdraw the liters from the bucket of the client, waiting for them if
+    // given how long.
+    pub(super) async fn withdraw(
+        &self,
+        client: &Client,
+        liters: usize,
+        wait: Option<Duration>,
+    ) -> Result<Level, Withheld> {
+        let pool = match &self.store {
+            Store::Memory => {
+                let bucket = self.bucket(client);
+
+                return match wait {
+                    Some(wait) => bucket.withdraw(liters, wait).await,
+                    None => bucket.try_withdraw(liters),
+                };
+            },
+            Store::Postgres(pool) => pool,
+        };
+
+        if self.sweep_due(Instant::now()) {
+            postgres::sweep(pool, self.full_after()).await?;
+        }
+
+        postgres::withdraw(pool, client, &self.tier(client).1, liters, wait.unwrap_or_default()).await
+    }
+
+    // The bucket of the client, a full one if it has none yet.
+    fn bucket(&self, client: &Client) -> Arc<MilkBucket> {
+        let now = Instant::now();
+
+        if self.sweep_due(now) {
+            self.sweep(now);
+        }
+
+        let mut entry = self.buckets.entry(client.clone()).or_insert_with(|| {
+            let (name, tier) = self.tier(client);
+
+            Entry {
+                bucket: Arc::new(MilkBucket::new(&tier)),
+                tier: name,
+                last_used: now,
+            }
+        });
+
+        entry.last_used = now;
+        entry.bucket.clone()
+    }
+
+    // How much milk the client has left, without withdrawing any.
+    pub(super) async fn level(&self, client: &Client) -> Result<Level, sqlx::Error> {
+        let tier = self.tier(client).1;
+
+        match &self.store {
+            Store::Memory => Ok(match self.buckets.get(client) {
+                Some(entry) => entry.bucket.level(Instant::now()),
+                None => Level::full(&tier),
+            }),
+            Store::Postgres(pool) => postgres::level(pool, client, &tier).await,
+        }
+    }
+
+    // Fill up the bucket of the client, or those of all of them, for those
+    // waiting in line too.
+    pub(super) async fn refill(&self, client: Option<&Client>) -> Result<(), sqlx::Error> {
+        let now = Instant::now();
+
+        match (&self.store, client) {
+            (Store::Memory, Some(client)) => if let Some(entry) = self.buckets.get(client) {
+                entry.bucket.fill_up(now);
+            },
+            (Store::Memory, None) => self.buckets.iter().for_each(|entry| entry.bucket.fill_up(now)),
+            (Store::Postgres(pool), client) => postgres::refill(pool, client).await?,
+        }
+
+        Ok(())
+    }
+
+    // Whether it is time to sweep, at most once per idle timeout.
+    fn sweep_due(&self, now: Instant) -> bool {
+        let mut last_sweep = self.last_sweep.lock().unwrap();
+
+        if now.duration_since(*last_sweep) < self.idle_timeout {
+            return false;
+        }
+
+        *last_sweep = now;
+
+        true
+    }
+
+    // How long after its last withdrawal any bucket is surely full again.
+    fn full_after(&self) -> Duration {
+        self.tiers.read().unwrap().values()
+            .map(fill_time)
+            .fold(self.idle_timeout, Duration::max)
+    }
+
+    // Drop the buckets idle for long enough to be full again, so that a new
+    // one is no different.
+    fn sweep(&self, now: Instant) {
+        self.buckets.retain(|_, entry| {
+            now.duration_since(entry.last_used) < self.idle_timeout.max(entry.bucket.fill_time())
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config() -> MilkConfig {
+        let mut config = MilkConfig::default();
+
+        config.tiers.insert(String::from("gold"), Tier { capacity: 50, refill: 10, interval: 1000 });
+        config.api_keys.insert(hash_api_key("moo"), String::from("gold"));
+        config
+    }
+
+    #[test]
+    fn test_bucket() {
+        let bucket = MilkBucket::new(&Tier { capacity: 5, refill: 2, interval: 1000 });
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+        let level = |remaining, next_refill: Option<u64>| Level {
+            capacity: 5,
+            remaining,
+            next_refill: next_refill.map(Duration::from_millis),
+        };
+        let short = |level, retry_after| Withheld::Empty(Shortage {
+            level,
+            retry_after: Duration::from_millis(retry_after),
+            position: None,
+        });
+
+        assert_eq!(bucket.try_withdraw_at(4, at(0)), Ok(level(1, Some(1000))));
+        assert_eq!(bucket.try_withdraw_at(2, at(400)), Err(short(level(1, Some(600)), 600)));
+        assert_eq!(bucket.try_withdraw_at(2, at(1000)), Ok(level(1, Some(1000))));
+        // Two intervals later, the bucket overflows.
+        assert_eq!(bucket.try_withdraw_at(0, at(3500)), Ok(level(5, None)));
+        // Refilled a full interval after being found full.
+        assert_eq!(bucket.try_withdraw_at(5, at(4000)), Ok(level(0, Some(1000))));
+        assert_eq!(bucket.try_withdraw_at(1, at(4999)), Err(short(level(0, Some(1)), 1)));
+        // Not just until the next refill.
+        assert_eq!(bucket.try_withdraw_at(5, at(4999)), Err(short(level(0, Some(1)), 2001)));
+        assert_eq!(bucket.try_withdraw_at(6, at(4999)), Err(Withheld::TooMuch(level(0, Some(1)))));
+        assert_eq!(bucket.try_withdraw_at(2, at(5000)), Ok(level(0, Some(1000))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_withdraw() {
+        let bucket = Arc::new(MilkBucket::new(&Tier { capacity: 5, refill: 1, interval: 1000 }));
+        let start = Instant::now();
+
+        assert!(bucket.withdraw(5, Duration::ZERO).await.is_ok());
+
+        // Served in turn, and meanwhile nobody jumps the line.
+        let first = tokio::spawn({
+            let bucket = bucket.clone();
+            async move { bucket.withdraw(3, Duration::from_secs(10)).await }
+        });
+        time::sleep(Duration::from_millis(10)).await;
+
+        let second = tokio::spawn({
+            let bucket = bucket.clone();
+            async move { bucket.withdraw(1, Duration::from_secs(10)).await }
+        });
+        time::sleep(Duration::from_millis(10)).await;
+
+        // Served after the 4 liters wanted in line.
+        match bucket.try_withdraw(1) {
+            Err(Withheld::Empty(shortage)) => assert_eq!(shortage.retry_after, Duration::from_millis(4980)),
+            _ => panic!("jumped the line"),
+        }
+
+        assert_eq!(first.await.unwrap().unwrap().remaining, 0);
+        assert_eq!(Instant::now() - start, Duration::from_secs(3));
+        assert_eq!(second.await.unwrap().unwrap().remaining, 0);
+        assert_eq!(Instant::now() - start, Duration::from_secs(4));
+
+        // Timing out behind someone else.
+        let first = tokio::spawn({
+            let bucket = bucket.clone();
+            async move { bucket.withdraw(5, Duration::from_secs(10)).await }
+        });
+        time::sleep(Duration::from_millis(10)).await;
+
+        let Err(Withheld::Empty(shortage)) = bucket.withdraw(1, Duration::from_secs(2)).await else {
+            panic!("not timed out");
+        };
+
+        // Enough for both by 10 seconds in.
+        assert_eq!(shortage.level.remaining, 2);
+        assert_eq!(shortage.retry_after, Duration::from_millis(3990));
+        assert_eq!(shortage.position, Some(2));
+        assert!(first.await.unwrap().is_ok());
+
+        // More than ever fits, right away.
+        assert!(matches!(bucket.withdraw(6, Duration::from_secs(10)).await, Err(Withheld::TooMuch(_))));
+        assert_eq!(Instant::now() - start, Duration::from_secs(9));
+    }
+
+    #[tokio::test]
+    async fn test_configure() {
+        let buckets = MilkBuckets::new(&config()).unwrap();
+        let alice = Client::Ip(IpAddr::from([10, 0, 0, 1]));
+        let gold = Client::ApiKey(hash_api_key("moo"));
+
+        assert!(buckets.bucket(&alice).try_withdraw(1).is_ok());
+        assert!(buckets.bucket(&gold).try_withdraw(10).is_ok());
+
+        buckets.configure("default", Tier { capacity: 2, refill: 1, interval: 60_000 }).unwrap();
+
+        // Clamped to the new capacity.
+        assert_eq!(buckets.level(&alice).await.unwrap(), Level { capacity: 2, remaining: 2, next_refill: None });
+        assert_eq!(buckets.level(&gold).await.unwrap().remaining, 40);
+        assert_eq!(buckets.tiers()["default"].capacity, 2);
+
+        assert!(buckets.bucket(&alice).try_withdraw(2).is_ok());
+        buckets.configure("default", Tier { capacity: 10, refill: 1, interval: 60_000 }).unwrap();
+
+        // Kept, and refilled from now on.
+        let level = buckets.level(&alice).await.unwrap();
+
+        assert_eq!(level.remaining, 0);
+        assert!(level.next_refill.unwrap() > Duration::from_secs(59));
+
+        // New buckets get the new settings.
+        assert_eq!(buckets.level(&Client::Everyone).await.unwrap().capacity, 10);
+
+        assert!(buckets.configure("platinum", Tier::default()).is_err());
+        assert!(buckets.configure("default", Tier { refill: 0, ..Tier::default() }).is_err());
+
+        // Not for PUT /9/config once the other instances share them.
+        assert!(!buckets.is_shared());
+        assert!(buckets.shared(PgPool::connect_lazy("postgres://localhost/milk").unwrap()).is_shared());
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(Client::Ip(IpAddr::from([10, 0, 0, 1])).to_string(), "ip:10.0.0.1");
+        assert_eq!(Client::ApiKey(String::from("ab12")).to_string(), "api_key:ab12");
+        assert_eq!(Client::Everyone.to_string(), "everyone");
+    }
+
+    #[test]
+    fn test_identify() {
+        let api_keys = config().api_keys;
+        let peer = Some(IpAddr::from([10, 0, 0, 1]));
+        let headers = |pairs: &[(&'static str, &str)]| {
+            let mut headers = HeaderMap::new();
+
+            for (name, value) in pairs {
+                headers.append(*name, value.parse().unwrap());
+            }
+
+            headers
+        };
+        let ip = |ip: &str| Some(Client::Ip(ip.parse().unwrap()));
+
+        assert_eq!(identify(&headers(&[]), peer, &api_keys, 0), ip("10.0.0.1"));
+        assert_eq!(identify(&headers(&[]), None, &api_keys, 0), None);
+        // Anyone can say they are forwarded for anyone.
+        assert_eq!(identify(&headers(&[("x-forwarded-for", "1.2.3.4")]), peer, &api_keys, 0), ip("10.0.0.1"));
+
+        // Behind a proxy, the peer is the proxy.
+        assert_eq!(identify(&headers(&[("x-forwarded-for", "1.2.3.4, 5.6.7.8")]), peer, &api_keys, 1), ip("5.6.7.8"));
+        assert_eq!(identify(&headers(&[("x-forwarded-for", "1.2.3.4, 5.6.7.8")]), peer, &api_keys, 2), ip("1.2.3.4"));
+        assert_eq!(
+            identify(&headers(&[("x-forwarded-for", "1.2.3.4"), ("x-forwarded-for", "::1")]), peer, &api_keys, 1),
+            ip("::1"),
+        );
+        assert_eq!(identify(&headers(&[]), peer, &api_keys, 1), None);
+        assert_eq!(identify(&headers(&[("x-forwarded-for", "1.2.3.4")]), peer, &api_keys, 2), None);
+        assert_eq!(identify(&headers(&[("x-forwarded-for", "nope")]), peer, &api_keys, 1), None);
+
+        assert_eq!(
+            identify(&headers(&[("x-api-key", "moo"), ("x-forwarded-for", "1.2.3.4")]), None, &api_keys, 1),
+            Some(Client::ApiKey(hash_api_key("moo"))),
+        );
+        assert_eq!(identify(&headers(&[("x-api-key", "oink")]), peer, &api_keys, 0), ip("10.0.0.1"));
+    }
+
+    #[tokio::test]
+    async fn test_buckets() {
+        let buckets = MilkBuckets::new(&config()).unwrap();
+        let alice = Client::Ip(IpAddr::from([10, 0, 0, 1]));
+        let bob = Client::Ip(IpAddr::from([10, 0, 0, 2]));
+        let gold = Client::ApiKey(hash_api_key("moo"));
+
+        assert!(buckets.bucket(&alice).try_withdraw(5).is_ok());
+        assert!(buckets.bucket(&alice).try_withdraw(1).is_err());
+        assert!(buckets.bucket(&bob).try_withdraw(5).is_ok());
+        assert!(buckets.bucket(&gold).try_withdraw(50).is_ok());
+
+        buckets.refill(Some(&alice)).await.unwrap();
+
+        assert!(buckets.bucket(&alice).try_withdraw(5).is_ok());
+        assert!(buckets.bucket(&bob).try_withdraw(1).is_err());
+
+        buckets.refill(None).await.unwrap();
+
+        assert!(buckets.bucket(&bob).try_withdraw(5).is_ok());
+        assert!(buckets.bucket(&gold).try_withdraw(50).is_ok());
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut config = config();
+
+        config.tiers.insert(String::from("slow"), Tier { capacity: 1000, refill: 1, interval: 1000 });
+        config.api_keys.insert(hash_api_key("oink"), String::from("slow"));
+
+        let buckets = MilkBuckets::new(&config).unwrap();
+        let start = Instant::now();
+
+        buckets.bucket(&Client::Ip(IpAddr::from([10, 0, 0, 1])));
+        buckets.bucket(&Client::ApiKey(hash_api_key("oink")));
+
+        // Not before the idle timeout.
+        assert!(!buckets.sweep_due(start + Duration::from_secs(60)));
+
+        // Nor before the bucket would be full again.
+        assert!(buckets.sweep_due(start + Duration::from_secs(11 * 60)));
+        buckets.sweep(start + Duration::from_secs(11 * 60));
+        assert_eq!(buckets.buckets.len(), 1);
+        assert_eq!(buckets.full_after(), Duration::from_secs(1000));
+
+        buckets.sweep(start + Duration::from_secs(22 * 60));
+        assert!(buckets.buckets.is_empty());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut config = config();
+
+        config.api_keys.insert(hash_api_key("oink"), String::from("platinum"));
+        assert!(MilkBuckets::new(&config).is_err());
+
+        let mut config = MilkConfig::default();
+
+        config.tiers.clear();
+        assert!(MilkBuckets::new(&config).is_err());
+
+        let mut config = MilkConfig::default();
+
+        config.tiers.insert(String::from("empty"), Tier { capacity: 0, ..Tier::default() });
+        assert!(MilkBuckets::new(&config).is_err());
+
+        let buckets = MilkBuckets::new(&MilkConfig::default()).unwrap();
+
+        for tier in [
+            Tier { capacity: usize::MAX, ..Tier::default() },
+            Tier { capacity: MAX_LITERS, refill: 1, interval: 1 },
+            Tier { interval: u64::MAX, ..Tier::default() },
+            Tier { capacity: 1 << 40, refill: 1, interval: u64::MAX },
+        ] {
+            assert!(buckets.configure(DEFAULT_TIER, tier).is_err());
+        }
+
+        assert!(buckets.configure(DEFAULT_TIER, Tier { capacity: MAX_LITERS, refill: 1000, interval: 1 }).is_ok());
+        assert_eq!(fill_time(&Tier { capacity: 1 << 40, refill: 1, interval: u64::MAX }), Duration::MAX);
+    }
+}
@@ -0,0 +1,441 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use axum::{
+    body::{self, Body},
+    extract::{ConnectInfo, MatchedPath, Query},
+    http::{self, HeaderMap, HeaderName, HeaderValue, Request, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use tokio::time::Duration;
+use tower::{Layer, Service};
+
+use crate::config::{Config, MilkConfig, RateLimitKey, Tier};
+
+use super::{bucket::{Client, Level, MilkBuckets, Shortage, Withheld}, telemetry::Telemetry};
+
+// Responds to the requests turned away, given how short of milk they are.
+type Rejection = Arc<dyn Fn(&Shortage) -> Response + Send + Sync>;
+
+// How many liters a request takes, given its headers and body. None if it
+// does not say in a way that makes sense.
+type Cost = Arc<dyn Fn(&HeaderMap, &[u8]) -> Option<usize> + Send + Sync>;
+
+// As large as axum lets JSON bodies be by default.
+const MAX_BODY: usize = 2 * 1024 * 1024;
+
+// Who is limited how, and told what when out of luck.
+#[derive(Clone)]
+pub(crate) struct Policy {
+    buckets: Arc<MilkBuckets>,
+    key: RateLimitKey,
+    rejection: Rejection,
+    // A liter per request without it.
+    cost: Option<Cost>,
+    // How long requests may ask to wait for their turn with ?wait=<ms>, if
+    // at all.
+    max_wait: Option<Duration>,
+    // Where the requests granted and refused are recorded, if anywhere.
+    telemetry: Option<Arc<Telemetry>>,
+}
+
+// Why a request was turned away, with the client whose bucket it was
+// withdrawing from, if known.
+enum Denial {
+    // Not enough left in its bucket.
+    Limited(Shortage),
+    // More than its bucket ever holds.
+    TooMuch(Level),
+    BadRequest(Option<Client>),
+    TooLarge(Client),
+    Internal(Client),
+}
+
+#[derive(Deserialize)]
+struct Wait {
+    // In milliseconds.
+    wait: Option<u64>,
+}
+
+impl Policy {
+    pub(crate) fn new(buckets: Arc<MilkBuckets>, key: RateLimitKey) -> Policy {
+        Policy {
+            buckets,
+            key,
+            rejection: Arc::new(|_| (
+                StatusCode::TOO_MANY_REQUESTS,
+                String::from("Too many requests\n"),
+            ).into_response()),
+            cost: None,
+            max_wait: None,
+            telemetry: None,
+        }
+    }
+
+    // The policy of a route in the rate_limits config, with API keys from the
+    // milk config.
+    fn from_config(config: &Config, route: &str) -> Result<Policy, String> {
+        let limit = &config.rate_limits[route];
+        let tier = Tier {
+            capacity: limit.capacity,
+            refill: limit.refill,
+            interval: limit.interval,
+        };
+        let api_keys = match limit.key {
+            RateLimitKey::Client => config.milk.api_keys.keys()
+                .map(|hash| (hash.clone(), String::from("default")))
+                .collect(),
+            RateLimitKey::Ip | RateLimitKey::Global => HashMap::new(),
+        };
+
+        // In memory, whatever the backend of the milk buckets.
+        let buckets = MilkBuckets::new(&MilkConfig {
+            idle_timeout: config.milk.idle_timeout,
+            tiers: HashMap::from([(String::from("default"), tier)]),
+            api_keys,
+            trusted_proxies: config.milk.trusted_proxies,
+            ..MilkConfig::default()
+        }).map_err(|e| format!("{}: {}", route, e))?;
+
+        Ok(Policy::new(Arc::new(buckets), limit.key))
+    }
+
+    pub(crate) fn with_rejection(
+        mut self,
+        rejection: impl Fn(&Shortage) -> Response + Send + Sync + 'static,
+    ) -> Policy {
+        self.rejection = Arc::new(rejection);
+        self
+    }
+
+    // Charge requests by what they ask for rather than a liter each, which
+    // takes buffering their bodies.
+    pub(crate) fn with_cost(
+        mut self,
+        cost: impl Fn(&HeaderMap, &[u8]) -> Option<usize> + Send + Sync + 'static,
+    ) -> Policy {
+        self.cost = Some(Arc::new(cost));
+        self
+    }
+
+    // Let requests wait in line for up to the given time, rather than being
+    // turned away as soon as there is not enough left.
+    pub(crate) fn with_waiting(mut self, max_wait: Duration) -> Policy {
+        self.max_wait = Some(max_wait);
+        self
+    }
+
+    pub(crate) fn with_telemetry(mut self, telemetry: Arc<Telemetry>) -> Policy {
+        self.telemetry = Some(telemetry);
+        self
+    }
+
+    // Withdraw from the bucket of the sender of the request, once there is
+    // enough left if it waits, and hand back the request.
+    async fn check(&self, request: Request<Body>) -> Result<(Request<Body>, Level), Denial> {
+        let client = self.client(&request).ok_or(Denial::BadRequest(None))?;
+
+        let wait = match self.max_wait {
+            Some(max_wait) => Query::<Wait>::try_from_uri(request.uri())
+                .map_err(|_| Denial::BadRequest(Some(client.clone())))?
+                .0
+                .wait
+                .map(|wait| Duration::from_millis(wait).min(max_wait)),
+            None => None,
+        };
+
+        let (request, liters) = match &self.cost {
+            Some(cost) => {
+                let (parts, body) = request.into_parts();
+                let bytes = match body::to_bytes(body, MAX_BODY).await {
+                    Ok(bytes) => bytes,
+                    Err(_) => return Err(Denial::TooLarge(client)),
+                };
+                let Some(liters) = cost(&parts.headers, &bytes) else {
+                    return Err(Denial::BadRequest(Some(client)));
+                };
+
+                (Request::from_parts(parts, Body::from(bytes)), liters)
+            },
+            None => (request, 1),
+        };
+
+        let withdrawn = self.buckets.withdraw(&client, liters, wait).await;
+
+        if let Some(telemetry) = &self.telemetry {
+            match &withdrawn {
+                Ok(_) => telemetry.record(&client, liters, true),
+                Err(Withheld::Empty(_) | Withheld::TooMuch(_)) => telemetry.record(&client, liters, false),
+                Err(Withheld::Database) => {},
+            }
+        }
+
+        let level = withdrawn.map_err(|e| match e {
+            Withheld::Empty(shortage) => Denial::Limited(shortage),
+            Withheld::TooMuch(level) => Denial::TooMuch(level),
+            Withheld::Database => Denial::Internal(client),
+        })?;
+
+        Ok((request, level))
+    }
+
+    fn client(&self, request: &Request<Body>) -> Option<Client> {
+        match self.key {
+            RateLimitKey::Global => Some(Client::Everyone),
+            RateLimitKey::Client | RateLimitKey::Ip => {
+                let peer = request.extensions().get::<ConnectInfo<SocketAddr>>()
+                    .map(|ConnectInfo(addr)| addr.ip());
+
+                self.buckets.identify(request.headers(), peer)
+            },
+        }
+    }
+
+    async fn deny(&self, denial: Denial) -> Response {
+        let (status, client) = match denial {
+            Denial::Limited(shortage) => return self.reject(shortage),
+            Denial::TooMuch(level) => {
+                let mut response = StatusCode::PAYLOAD_TOO_LARGE.into_response();
+
+                // Without Retry-After, as it never gets any better.
+                response.headers_mut().extend(rate_limit_headers(&level, None));
+
+                return response;
+            },
+            Denial::BadRequest(client) => (StatusCode::BAD_REQUEST, client),
+            Denial::TooLarge(client) => (StatusCode::PAYLOAD_TOO_LARGE, Some(client)),
+            Denial::Internal(client) => (StatusCode::INTERNAL_SERVER_ERROR, Some(client)),
+        };
+
+        // Nothing was withdrawn, so the bucket is as it was.
+        let level = match &client {
+            Some(client) => self.buckets.level(client).await.ok(),
+            None => None,
+        };
+        let headers = match level {
+            Some(level) => rate_limit_headers(&level, None),
+            // At least the limit, if the bucket cannot be told.
+            None => HeaderMap::from_iter([(
+                HeaderName::from_static("ratelimit-limit"),
+                HeaderValue::from(self.buckets.capacity(client.as_ref())),
+            )]),
+        };
+        let mut response = status.into_response();
+
+        response.headers_mut().extend(headers);
+        response
+    }
+
+    fn reject(&self, shortage: Shortage) -> Response {
+        let mut response = (self.rejection)(&shortage);
+
+        response.headers_mut().extend(rate_limit_headers(&shortage.level, Some(shortage.retry_after)));
+
+        if let Some(position) = shortage.position {
+            response.headers_mut().insert("queue-position", HeaderValue::from(position));
+        }
+
+        response
+    }
+}
+
+// In whole seconds, rounded up so that retrying then succeeds.
+fn seconds(duration: Duration) -> u64 {
+    duration.as_millis().div_ceil(1000).try_into().unwrap_or(u64::MAX)
+}
+
+// The RateLimit-* headers of the IETF draft for the level of a bucket, with
+// Retry-After if the request has to be retried after that long. Either way
+// the reset is when it could be.
+fn rate_limit_headers(level: &Level, retry_after: Option<Duration>) -> HeaderMap {
+    let reset = retry_after.or(level.next_refill).map_or(0, seconds);
+    let mut headers = HeaderMap::new();
+
+    headers.insert("ratelimit-limit", HeaderValue::from(level.capacity));
+    headers.insert("ratelimit-remaining", HeaderValue::from(level.remaining));
+    headers.insert("ratelimit-reset", HeaderValue::from(reset));
+
+    if retry_after.is_some() {
+        headers.insert(http::header::RETRY_AFTER, HeaderValue::from(reset));
+    }
+
+    headers
+}
+
+#[derive(Clone)]
+enum Policies {
+    // The same for every request.
+    All(Policy),
+    // By method and matched route, as in "POST /16/wrap".
+    ByRoute(Arc<HashMap<String, Policy>>),
+}
+
+// Rate limits the routes or router it is attached to. Every response tells
+// how much is left of the bucket it was served from.
+#[derive(Clone)]
+pub(crate) struct RateLimitLayer(Policies);
+
+impl RateLimitLayer {
+    pub(crate) fn new(policy: Policy) -> RateLimitLayer {
+        RateLimitLayer(Policies::All(policy))
+    }
+
+    // The policies of the rate_limits config, for a route layer of the whole
+    // router.
+    pub(crate) fn from_config(config: &Config) -> Result<RateLimitLayer, String> {
+        let policies = config.rate_limits.keys()
+            .map(|route| Ok((route.clone(), Policy::from_config(config, route)?)))
+            .collect::<Result<_, String>>()?;
+
+        Ok(RateLimitLayer(Policies::ByRoute(Arc::new(policies))))
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            policies: self.0.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RateLimit<S> {
+    inner: S,
+    policies: Policies,
+}
+
+impl<S> RateLimit<S> {
+    fn policy(&self, request: &Request<Body>) -> Option<Policy> {
+        match &self.policies {
+            Policies::All(policy) => Some(policy.clone()),
+            Policies::ByRoute(policies) => request.extensions()
+                .get::<MatchedPath>()
+                .and_then(|path| policies.get(&format!("{} {}", request.method(), path.as_str())))
+                .cloned(),
+        }
+    }
+}
+
+impl<S> Service<Request<Body>> for RateLimit<S>
+where
+    S: Service<Request<Body>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let policy = self.policy(&request);
+
+        // The clone might not be ready, unlike the one poll_ready was called on.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let (request, headers) = match policy {
+                Some(policy) => match policy.check(request).await {
+                    Ok((request, level)) => (request, rate_limit_headers(&level, None)),
+                    Err(denial) => return Ok(policy.deny(denial).await),
+                },
+                None => (request, HeaderMap::new()),
+            };
+
+            let mut response = inner.call(request).await?;
+
+            response.headers_mut().extend(headers);
+
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{routing::post, Router};
+    use tower::ServiceExt;
+
+    use super::*;
+
+    #[test]
+    fn test_rate_limit_headers() {
+        let level = Level {
+            capacity: 5,
+            remaining: 0,
+            next_refill: Some(tokio::time::Duration::from_millis(1200)),
+        };
+        let headers = rate_limit_headers(&level, None);
+
+        assert_eq!(headers["ratelimit-limit"], "5");
+        assert_eq!(headers["ratelimit-remaining"], "0");
+        assert_eq!(headers["ratelimit-reset"], "2");
+        assert!(!headers.contains_key(http::header::RETRY_AFTER));
+
+        // Until there is enough for the request, not just the next refill.
+        let headers = rate_limit_headers(&level, Some(tokio::time::Duration::from_millis(4100)));
+
+        assert_eq!(headers["ratelimit-reset"], "5");
+        assert_eq!(headers[http::header::RETRY_AFTER], "5");
+
+        let level = Level { remaining: 5, next_refill: None, ..level };
+        let headers = rate_limit_headers(&level, None);
+
+        assert_eq!(headers["ratelimit-reset"], "0");
+        assert!(!headers.contains_key(http::header::RETRY_AFTER));
+    }
+
+    #[tokio::test]
+    async fn test_layer() {
+        let buckets = MilkBuckets::new(&MilkConfig {
+            tiers: HashMap::from([(String::from("default"), Tier { capacity: 2, refill: 1, interval: 60_000 })]),
+            ..MilkConfig::default()
+        }).unwrap();
+        let policy = Policy::new(Arc::new(buckets), RateLimitKey::Global)
+            .with_waiting(Duration::from_secs(1));
+        let app = Router::new()
+            .route("/", post(|| async { "Moo\n" }))
+            .layer(RateLimitLayer::new(policy));
+        let call = |uri: &'static str| app.clone().oneshot(Request::post(uri).body(Body::empty()).unwrap());
+
+        let response = call("/").await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["ratelimit-limit"], "2");
+        assert_eq!(response.headers()["ratelimit-remaining"], "1");
+        assert_eq!(response.headers()["ratelimit-reset"], "60");
+        assert!(!response.headers().contains_key(http::header::RETRY_AFTER));
+
+        assert_eq!(call("/").await.unwrap().status(), StatusCode::OK);
+
+        let response = call("/").await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()["ratelimit-limit"], "2");
+        assert_eq!(response.headers()["ratelimit-remaining"], "0");
+        assert_eq!(response.headers()["ratelimit-reset"], "60");
+        assert_eq!(response.headers()[http::header::RETRY_AFTER], "60");
+
+        // Turned away before withdrawing anything.
+        let response = call("/?wait=soon").await.unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(response.headers()["ratelimit-limit"], "2");
+        assert_eq!(response.headers()["ratelimit-remaining"], "0");
+        assert!(!response.headers().contains_key(http::header::RETRY_AFTER));
+    }
+}
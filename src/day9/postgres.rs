@@ -0,0 +1,179 @@
+use sqlx::{query, query_scalar, PgPool};
+use tokio::time::{self, Duration, Instant};
+
+use crate::config::Tier;
+
+use super::bucket::{Client, Level, Shortage, Withheld};
+
+// The buckets shared by the instances are rows of milk_buckets, with the
+// liters they held when they were last refilled. They refill continuously
+// rather than every interval, so that a withdrawal is a single UPDATE however
+// long it has been, much like GCRA. The time is that of the database, the
+// only clock the instances agree on.
+//
+// There is no line across the instances: those waiting try again when there
+// should be enough milk.
+
+// The liters in the bucket by now, given its capacity ($2) and liters per
+// millisecond ($3).
+const FILLED: &str = "LEAST($2, liters + $3 * GREATEST(EXTRACT(EPOCH FROM CURRENT_TIMESTAMP - refilled_at)::float8 * 1000, 0))";
+
+// Liters per millisecond.
+fn rate(tier: &Tier) -> f64 {
+    tier.refill as f64 / tier.interval as f64
+}
+
+fn level_of(tier: &Tier, liters: f64) -> Level {
+    let remaining = (liters.floor() as usize).min(tier.capacity);
+
+    Level {
+        capacity: tier.capacity,
+        remaining,
+        // Until the next whole liter.
+        next_refill: (remaining < tier.capacity)
+            .then(|| Duration::from_secs_f64((remaining as f64 + 1.0 - liters).max(0.0) / rate(tier) / 1000.0)),
+    }
+}
+
+// Withdraw the liters if there are enough, and respond with the liters left
+// either way.
+async fn try_withdraw(
+    pool: &PgPool,
+    key: &str,
+    tier: &Tier,
+    liters: usize,
+) -> Result<Result<f64, f64>, sqlx::Error> {
+    let withdraw = format!(r#"
+        UPDATE milk_buckets
+        SET liters = {FILLED} - $4, refilled_at = CURRENT_TIMESTAMP
+        WHERE key = $1 AND {FILLED} >= $4
+        RETURNING liters
+    "#);
+    let peek = format!("SELECT {FILLED} FROM milk_buckets WHERE key = $1");
+
+    loop {
+        let left: Option<f64> = query_scalar(&withdraw)
+            .bind(key)
+            .bind(tier.capacity as f64)
+            .bind(rate(tier))
+            .bind(liters as f64)
+            .fetch_optional(pool)
+            .await?;
+
+        if let Some(left) = left {
+            return Ok(Ok(left));
+        }
+
+        let available: Option<f64> = query_scalar(&peek)
+            .bind(key)
+            .bind(tier.capacity as f64)
+            .bind(rate(tier))
+            .fetch_optional(pool)
+            .await?;
+
+        if let Some(available) = available {
+            return Ok(Err(available));
+        }
+
+        // A client without a bucket yet gets a full one.
+        query(r#"
+            INSERT INTO milk_buckets (key, liters, refilled_at)
+            VALUES ($1, $2, CURRENT_TIMESTAMP)
+            ON CONFLICT (key) DO NOTHING
+        "#)
+            .bind(key)
+            .bind(tier.capacity as f64)
+            .execute(pool)
+            .await?;
+    }
+}
+
+// Withdraw the liters from the bucket of the client, trying again for at most
+// the timeout until there are enough.
+pub(super) async fn withdraw(
+    pool: &PgPool,
+    client: &Client,
+    tier: &Tier,
+    liters: usize,
+    timeout: Duration,
+) -> Result<Level, Withheld> {
+    if liters > tier.capacity {
+        return Err(Withheld::TooMuch(level(pool, client, tier).await?));
+    }
+
+    let key = client.to_string();
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let available = match try_withdraw(pool, &key, tier, liters).await? {
+            Ok(left) => return Ok(level_of(tier, left)),
+            Err(available) => available,
+        };
+
+        let now = Instant::now();
+        // Unless someone else takes it first. The liters were read after the
+        // UPDATE, so there may be enough already.
+        let enough_in = Duration::from_secs_f64((liters as f64 - available).max(0.0) / rate(tier) / 1000.0);
+
+        if now >= deadline {
+            return Err(Withheld::Empty(Shortage {
+                level: level_of(tier, available),
+                retry_after: enough_in,
+                position: None,
+            }));
+        }
+
+        time::sleep_until((now + enough_in).min(deadline)).await;
+    }
+}
+
+// How much milk the client has left, without withdrawing any.
+pub(super) async fn level(pool: &PgPool, client: &Client, tier: &Tier) -> Result<Level, sqlx::Error> {
+    let available: Option<f64> = query_scalar(&format!("SELECT {FILLED} FROM milk_buckets WHERE key = $1"))
+        .bind(client.to_string())
+        .bind(tier.capacity as f64)
+        .bind(rate(tier))
+        .fetch_optional(pool)
+        .await?;
+
+    Ok(available.map_or_else(|| Level::full(tier), |liters| level_of(tier, liters)))
+}
+
+// Fill up the bucket of the client, or those of all of them, by forgetting
+// about them.
+pub(super) async fn refill(pool: &PgPool, client: Option<&Client>) -> Result<(), sqlx::Error> {
+    match client {
+        Some(client) => query("DELETE FROM milk_buckets WHERE key = $1")
+            .bind(client.to_string())
+            .execute(pool)
+            .await?,
+        None => query("DELETE FROM milk_buckets").execute(pool).await?,
+    };
+
+    Ok(())
+}
+
+// Forget about the buckets untouched for long enough to be full again.
+pub(super) async fn sweep(pool: &PgPool, full_after: Duration) -> Result<(), sqlx::Error> {
+    query("DELETE FROM milk_buckets WHERE refilled_at < CURRENT_TIMESTAMP - make_interval(secs => $1)")
+        .bind(full_after.as_secs_f64())
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_level() {
+        let tier = Tier { capacity: 5, refill: 2, interval: 1000 };
+
+        assert_eq!(level_of(&tier, 5.0), Level { capacity: 5, remaining: 5, next_refill: None });
+        assert_eq!(level_of(&tier, 2.0), Level { capacity: 5, remaining: 2, next_refill: Some(Duration::from_millis(500)) });
+        assert_eq!(level_of(&tier, 2.5).next_refill, Some(Duration::from_millis(250)));
+        assert_eq!(level_of(&tier, 4.9999999).remaining, 4);
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex, Weak},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use sqlx::{query, PgPool};
+use tokio::time::{self, Duration};
+
+use crate::config::TelemetryConfig;
+
+use super::bucket::Client;
+
+// A withdrawal granted or refused.
+#[derive(Clone)]
+struct Event {
+    // In seconds since the epoch.
+    at: u64,
+    client: String,
+    liters: usize,
+    granted: bool,
+}
+
+// The latest outcomes of the withdrawals from the milk buckets.
+pub(crate) struct Telemetry {
+    events: Mutex<VecDeque<Event>>,
+    capacity: usize,
+    // The events since the last flush, at most as many as are kept, if they
+    // are flushed at all.
+    pending: Option<Arc<Mutex<Vec<Event>>>>,
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub(super) struct Minute {
+    // In seconds since the epoch.
+    minute: u64,
+    granted: usize,
+    refused: usize,
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub(super) struct Consumer {
+    client: String,
+    // Liters withdrawn.
+    liters: usize,
+    granted: usize,
+    refused: usize,
+}
+
+#[derive(Serialize, PartialEq, Debug)]
+pub(super) struct Stats {
+    // Those with any withdrawals, the oldest first.
+    minutes: Vec<Minute>,
+    // By the liters they withdrew.
+    top_consumers: Vec<Consumer>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+impl Telemetry {
+    pub(crate) fn new(config: &TelemetryConfig) -> Telemetry {
+        Telemetry {
+            events: Mutex::new(VecDeque::with_capacity(config.capacity)),
+            capacity: config.capacity,
+            pending: None,
+        }
+    }
+
+    // The same telemetry, appended to the milk_events table as well by a task
+    // of its own, for as long as the telemetry is around.
+    pub(crate) fn flushed_to(self, pool: PgPool, config: &TelemetryConfig) -> Telemetry {
+        let pending = Arc::new(Mutex::new(Vec::new()));
+        // An interval of no time at all would never let anything else run.
+        let interval = Duration::from_secs(config.flush_interval.max(1));
+
+        tokio::spawn(flush(pool, Arc::downgrade(&pending), interval));
+
+        Telemetry {
+            pending: Some(pending),
+            ..self
+        }
+    }
+
+    pub(super) fn record(&self, client: &Client, liters: usize, granted: bool) {
+        self.record_event(Event {
+            at: now(),
+            client: client.to_string(),
+            liters,
+            granted,
+        });
+    }
+
+    fn record_event(&self, event: Event) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if let Some(pending) = &self.pending {
+            let mut pending = pending.lock().unwrap();
+
+            if pending.len() < self.capacity {
+                pending.push(event.clone());
+            }
+        }
+
+        let mut events = self.events.lock().unwrap();
+
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+
+        events.push_back(event);
+    }
+
+    // The withdrawals of the last window seconds, per minute and by client,
+    // with that many of the clients who withdrew the most.
+    pub(super) fn stats(&self, window: u64, top: usize) -> Stats {
+        self.stats_at(now(), window, top)
+    }
+
+    fn stats_at(&self, now: u64, window: u64, top: usize) -> Stats {
+        let mut minutes: Vec<Minute> = Vec::new();
+        let mut consumers: HashMap<&str, Consumer> = HashMap::new();
+
+        let events = self.events.lock().unwrap();
+
+        for event in events.iter().filter(|event| now.saturating_sub(event.at) < window) {
+            let minute = event.at / 60 * 60;
+
+            // Recorded in order, give or take the clock.
+            let index = match minutes.iter().rposition(|m| m.minute <= minute) {
+                Some(i) if minutes[i].minute == minute => i,
+                position => {
+                    let i = position.map_or(0, |i| i + 1);
+
+                    minutes.insert(i, Minute { minute, granted: 0, refused: 0 });
+                    i
+                },
+            };
+
+            let consumer = consumers.entry(&event.client).or_insert_with(|| Consumer {
+                client: event.client.clone(),
+                liters: 0,
+                granted: 0,
+                refused: 0,
+            });
+
+            if event.granted {
+                minutes[index].granted += 1;
+                consumer.liters += event.liters;
+                consumer.granted += 1;
+            } else {
+                minutes[index].refused += 1;
+                consumer.refused += 1;
+            }
+        }
+
+        let mut top_consumers: Vec<Consumer> = consumers.into_values().collect();
+
+        top_consumers.sort_by(|a, b| b.liters.cmp(&a.liters).then_with(|| a.client.cmp(&b.client)));
+        top_consumers.truncate(top);
+
+        Stats { minutes, top_consumers }
+    }
+}
+
+// Insert the pending events every interval, until the telemetry is dropped.
+async fn flush(pool: PgPool, pending: Weak<Mutex<Vec<Event>>>, interval: Duration) {
+    let mut interval = time::interval(interval);
+
+    interval.set_missed_tick_behavior(time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+
+        let Some(pending) = pending.upgrade() else {
+            return;
+        };
+        let events = std::mem::take(&mut *pending.lock().unwrap());
+
+        drop(pending);
+        insert(&pool, events).await;
+    }
+}
+
+// Telemetry is best effort: a batch that fails to be inserted is lost.
+async fn insert(pool: &PgPool, events: Vec<Event>) {
+    if events.is_empty() {
+        return;
+    }
+
+    let _ = query(r#"
+        INSERT INTO milk_events (at, client, liters, granted)
+        SELECT to_timestamp(at), client, liters, granted
+        FROM UNNEST($1::float8[], $2::text[], $3::int8[], $4::bool[]) AS e (at, client, liters, granted)
+    "#)
+        .bind(events.iter().map(|event| event.at as f64).collect::<Vec<_>>())
+        .bind(events.iter().map(|event| event.client.clone()).collect::<Vec<_>>())
+        .bind(events.iter().map(|event| event.liters as i64).collect::<Vec<_>>())
+        .bind(events.iter().map(|event| event.granted).collect::<Vec<_>>())
+        .execute(pool)
+        .await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(at: u64, client: &str, liters: usize, granted: bool) -> Event {
+        Event { at, client: String::from(client), liters, granted }
+    }
+
+    #[test]
+    fn test_ring() {
+        let telemetry = Telemetry::new(&TelemetryConfig { capacity: 3, ..TelemetryConfig::default() });
+
+        for at in 0..5 {
+            telemetry.record_event(event(at, "ip:10.0.0.1", 1, true));
+        }
+
+        let events = telemetry.events.lock().unwrap();
+
+        assert_eq!(events.iter().map(|event| event.at).collect::<Vec<_>>(), [2, 3, 4]);
+    }
+
+    #[test]
+    fn test_stats() {
+        let telemetry = Telemetry::new(&TelemetryConfig::default());
+
+        telemetry.record_event(event(50, "ip:10.0.0.1", 5, true));
+        telemetry.record_event(event(600, "ip:10.0.0.1", 5, true));
+        telemetry.record_event(event(610, "api_key:ab12", 2, true));
+        telemetry.record_event(event(659, "ip:10.0.0.1", 1, false));
+        telemetry.record_event(event(700, "api_key:ab12", 3, true));
+        telemetry.record_event(event(710, "ip:10.0.0.2", 1, true));
+
+        let stats = telemetry.stats_at(719, 120, 2);
+
+        assert_eq!(stats.minutes, [
+            Minute { minute: 600, granted: 2, refused: 1 },
+            Minute { minute: 660, granted: 2, refused: 0 },
+        ]);
+        assert_eq!(stats.top_consumers, [
+            Consumer { client: String::from("api_key:ab12"), liters: 5, granted: 2, refused: 0 },
+            Consumer { client: String::from("ip:10.0.0.1"), liters: 5, granted: 1, refused: 1 },
+        ]);
+
+        assert!(telemetry.stats_at(10_000, 60, 10).minutes.is_empty());
+    }
+}
@@ -0,0 +1,199 @@
+use serde_json::{Map, Number, Value};
+
+// A unit of volume, by its exact size in liters, and the names it goes by.
+struct Unit {
+    names: &'static [&'static str],
+    liters: f64,
+}
+
+// The US customary and imperial units are defined in terms of the cubic inch
+// (16.387064 ml) and the liter respectively, so these factors are exact, up
+// to floating point.
+const UNITS: &[Unit] = &[
+    Unit { names: &["ml", "milliliters", "millilitres"], liters: 0.001 },
+    Unit { names: &["l", "liters", "litres"], liters: 1.0 },
+    Unit { names: &["gallons", "us_gallons"], liters: 3.785411784 },
+    Unit { names: &["imperial_gallons"], liters: 4.54609 },
+    Unit { names: &["quarts", "us_quarts"], liters: 0.946352946 },
+    Unit { names: &["imperial_quarts"], liters: 1.1365225 },
+    Unit { names: &["us_pints"], liters: 0.473176473 },
+    Unit { names: &["imperial_pints"], liters: 0.56826125 },
+    Unit { names: &["cups", "us_cups"], liters: 0.2365882365 },
+    Unit { names: &["imperial_cups"], liters: 0.284130625 },
+    Unit { names: &["fluid_ounces", "us_fluid_ounces"], liters: 0.0295735295625 },
+    Unit { names: &["imperial_fluid_ounces"], liters: 0.0284130625 },
+];
+
+// The bare pints of the litres and pints conversion, imperial unlike the other
+// bare units. They can be converted from, and are what litres are converted
+// into without a target, but a target of pints has to say which.
+const LEGACY_PINTS: &str = "pints";
+
+fn unit(name: &str) -> Option<&'static Unit> {
+    let name = if name == LEGACY_PINTS { "imperial_pints" } else { name };
+
+    UNITS.iter().find(|unit| unit.names.contains(&name))
+}
+
+// Where an amount goes without a target unit: gallons and liters into each
+// other as ever, and so litres and pints, and anything else into liters.
+fn default_target(name: &str) -> &'static str {
+    match name {
+        "gallons" => "liters",
+        "liters" => "gallons",
+        "litres" => "pints",
+        "pints" => "litres",
+        _ => "liters",
+    }
+}
+
+// Why an amount could not be converted.
+#[derive(PartialEq, Debug)]
+pub(super) enum ConversionError {
+    NoAmount,
+    // Amounts in several units at once.
+    Ambiguous,
+    NotANumber,
+    UnknownUnit,
+    UnknownTarget,
+}
+
+impl ConversionError {
+    pub(super) fn code(&self) -> &'static str {
+        match self {
+            ConversionError::NoAmount => "no_amount",
+            ConversionError::Ambiguous => "ambiguous",
+            ConversionError::NotANumber => "not_a_number",
+            ConversionError::UnknownUnit => "unknown_unit",
+            ConversionError::UnknownTarget => "unknown_target",
+        }
+    }
+}
+
+// Convert the amount in a request like {"gallons": 2} or {"gallons": 2, "to":
+// "ml"} into a response like {"ml": 7570.823568}, rounded to the decimal
+// places given if any.
+pub(super) fn convert(
+    request: &Map<String, Value>,
+    precision: Option<u32>,
+) -> Result<Map<String, Value>, ConversionError> {
+    let to = match request.get("to") {
+        Some(Value::String(to)) if to != LEGACY_PINTS => Some(to.as_str()),
+        Some(_) => return Err(ConversionError::UnknownTarget),
+        None => None,
+    };
+
+    // Only those in known units are amounts, the other fields are left alone.
+    let mut amounts = request.iter()
+        .filter_map(|(name, amount)| unit(name).map(|unit| (name, unit, amount)));
+
+    let (name, from, amount) = match (amounts.next(), amounts.next()) {
+        (Some((name, from, amount)), None) => (name, from, amount.as_f64().ok_or(ConversionError::NotANumber)?),
+        (Some(_), Some(_)) => return Err(ConversionError::Ambiguous),
+        // Something that looks like an amount, in some other unit.
+        (None, _) if request.iter().any(|(name, value)| name != "to" && value.is_number()) => {
+            return Err(ConversionError::UnknownUnit)
+        },
+        (None, _) => return Err(ConversionError::NoAmount),
+    };
+
+    let to = to.unwrap_or_else(|| default_target(name));
+    let liters = amount * from.liters;
+    let mut converted = liters / unit(to).ok_or(ConversionError::UnknownTarget)?.liters;
+
+    if let Some(precision) = precision {
+        let scale = 10f64.powi(precision as i32);
+
+        // Past 2^53 there are no fractions left to round off, and scaling
+        // could overflow.
+        if (converted.abs() * scale) < 2f64.powi(53) {
+            converted = (converted * scale).round() / scale;
+        }
+    }
+
+    // Only infinite amounts have no JSON number.
+    let converted = Number::from_f64(converted).ok_or(ConversionError::NotANumber)?;
+
+    Ok(Map::from_iter([(String::from(to), Value::Number(converted))]))
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn convert(request: Value) -> Option<Value> {
+        super::convert(request.as_object().unwrap(), None).ok().map(Value::Object)
+    }
+
+    fn amount(response: Option<Value>, name: &str) -> f64 {
+        response.unwrap()[name].as_f64().unwrap()
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert!((amount(convert(json!({"gallons": 1})), "liters") - 3.785411784).abs() < 1e-9);
+        assert!((amount(convert(json!({"liters": 3.785411784})), "gallons") - 1.0).abs() < 1e-9);
+        assert!((amount(convert(json!({"litres": 2})), "pints") - 3.519507972).abs() < 1e-9);
+        assert!((amount(convert(json!({"pints": 1})), "litres") - 0.56826125).abs() < 1e-9);
+        assert!((amount(convert(json!({"cups": 4})), "liters") - 0.946352946).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_to() {
+        assert!((amount(convert(json!({"gallons": 2, "to": "ml"})), "ml") - 7570.823568).abs() < 1e-9);
+        assert!((amount(convert(json!({"gallons": 1, "to": "quarts"})), "quarts") - 4.0).abs() < 1e-9);
+        assert!((amount(convert(json!({"imperial_gallons": 1, "to": "imperial_pints"})), "imperial_pints") - 8.0).abs() < 1e-9);
+        assert!((amount(convert(json!({"gallons": 1, "to": "us_pints"})), "us_pints") - 8.0).abs() < 1e-9);
+        assert!((amount(convert(json!({"us_pints": 1, "to": "fluid_ounces"})), "fluid_ounces") - 16.0).abs() < 1e-9);
+        assert!((amount(convert(json!({"imperial_pints": 1, "to": "imperial_fluid_ounces"})), "imperial_fluid_ounces") - 20.0).abs() < 1e-9);
+        assert!((amount(convert(json!({"l": 1, "to": "litres"})), "litres") - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_other_fields() {
+        assert_eq!(convert(json!({"liters": 1, "note": "x"})), convert(json!({"liters": 1})));
+        assert_eq!(convert(json!({"gallons": 1, "to": "ml", "from": "the fridge"})), convert(json!({"gallons": 1, "to": "ml"})));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(convert(json!({})), None);
+        assert_eq!(convert(json!({"to": "ml"})), None);
+        assert_eq!(convert(json!({"gallons": 1, "liters": 2})), None);
+        assert_eq!(convert(json!({"hogsheads": 1})), None);
+        assert_eq!(convert(json!({"gallons": 1, "to": "hogsheads"})), None);
+        assert_eq!(convert(json!({"gallons": 1, "to": 5})), None);
+        assert_eq!(convert(json!({"gallons": "1"})), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |request: Value| super::convert(request.as_object().unwrap(), None).unwrap_err();
+
+        assert_eq!(error(json!({})), ConversionError::NoAmount);
+        assert_eq!(error(json!({"to": "ml"})), ConversionError::NoAmount);
+        assert_eq!(error(json!({"gallons": 1, "liters": 2})), ConversionError::Ambiguous);
+        assert_eq!(error(json!({"gallons": "1"})), ConversionError::NotANumber);
+        assert_eq!(error(json!({"hogsheads": 1})), ConversionError::UnknownUnit);
+        assert_eq!(error(json!({"hogsheads": 1, "note": "x"})), ConversionError::UnknownUnit);
+        assert_eq!(error(json!({"note": "x"})), ConversionError::NoAmount);
+        assert_eq!(error(json!({"gallons": 1, "to": "hogsheads"})), ConversionError::UnknownTarget);
+        // US or imperial?
+        assert_eq!(error(json!({"gallons": 1, "to": "pints"})), ConversionError::UnknownTarget);
+        assert_eq!(error(json!({"gallons": 1e308, "to": "ml"})), ConversionError::NotANumber);
+    }
+
+    #[test]
+    fn test_precision() {
+        let convert = |request: Value, precision| {
+            Value::Object(super::convert(request.as_object().unwrap(), Some(precision)).unwrap())
+        };
+
+        assert_eq!(convert(json!({"gallons": 1}), 2), json!({"liters": 3.79}));
+        assert_eq!(convert(json!({"gallons": 2, "to": "ml"}), 0), json!({"ml": 7571.0}));
+        assert_eq!(convert(json!({"pints": 1}), 4), json!({"litres": 0.5683}));
+        assert_eq!(convert(json!({"liters": 1e300}), 15), json!({"gallons": 1e300 / 3.785411784}));
+    }
+}
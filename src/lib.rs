@@ -0,0 +1,12 @@
+// The Connect 4 boards of day 12 and their solver stand on their own, so they
+// are a library of their own too: the service plays on the bitboard, and the
+// benchmarks compare it with the board it replaced and time the solver.
+
+#[path = "day12/board.rs"]
+pub mod board;
+
+#[path = "day12/reference.rs"]
+pub mod reference;
+
+#[path = "day12/solver.rs"]
+pub mod solver;